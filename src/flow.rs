@@ -0,0 +1,192 @@
+//! Flow control: how many chunks a receiver lets a sender have in flight at once.
+//!
+//! Every TOKEN is a credit for one chunk. A `CreditWindow` keeps track of the credits a stream
+//! has outstanding, and sizes the window to twice the bandwidth-delay product it measures: the
+//! rate at which chunks arrive, times the shortest round trip seen between a TOKEN going out and
+//! a chunk coming back. A fast, close sender gets a small window, so little is buffered, and a
+//! distant one gets enough credits to keep the link busy.
+//!
+//! A credit whose TOKEN or chunk is lost never comes back. A stream delivers in order, so when
+//! chunks are asked for by offset, a chunk arriving means the ones asked for before it on the
+//! same stream aren't coming, and their credits are written off there and then. Otherwise a lost
+//! credit can't be told from a slow one, so a stream only counts as stuck once nothing has
+//! happened on it for a retransmission timeout, and then the credits it has out are written off.
+//! The timeout doubles each time that happens, until chunks start arriving again, so a sender that
+//! has merely stalled isn't flooded with credits.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How much each new gap between chunks counts towards the smoothed one.
+const INTERVAL_SMOOTHING: f64 = 0.125;
+/// The retransmission timeout is this many times the shortest round trip plus the usual gap
+/// between chunks, but never less than `MIN_RTO_MS`. Before anything has been measured, it's
+/// `INITIAL_RTO_MS`.
+const RTO_FACTOR: f64 = 4.0;
+const MIN_RTO_MS: u64 = 200;
+const INITIAL_RTO_MS: u64 = 1000;
+/// How many times the retransmission timeout can double while nothing arrives.
+const MAX_RTO_BACKOFF: u32 = 6;
+
+pub struct CreditWindow {
+  min: usize,
+  max: usize,
+  window: usize,
+  outstanding: usize,
+  issued: VecDeque<(Instant, Option<usize>)>,  // when each outstanding credit went out and for which offset, oldest first
+  min_rtt: Option<Duration>,
+  last_arrival: Option<Instant>,
+  interval: Option<f64>,  // smoothed seconds between chunks
+  backoff: u32  // how many times the retransmission timeout has doubled
+}
+
+impl CreditWindow {
+  /// A window of `initial` chunks, which will stay between `min` and `max` however the link
+  /// turns out to behave.
+  pub fn new(initial: usize, min: usize, max: usize) -> CreditWindow {
+    let min = cmp::max(min, 1);
+    let max = cmp::max(max, min);
+    CreditWindow {
+      min: min,
+      max: max,
+      window: cmp::min(cmp::max(initial, min), max),
+      outstanding: 0,
+      issued: VecDeque::new(),
+      min_rtt: None,
+      last_arrival: None,
+      interval: None,
+      backoff: 0
+    }
+  }
+
+  /// How many chunks the sender may currently have in flight.
+  pub fn window(&self) -> usize {
+    self.window
+  }
+
+  /// How many credits have gone out without a chunk coming back.
+  pub fn outstanding(&self) -> usize {
+    self.outstanding
+  }
+
+  /// How many more credits to hand out to fill the window.
+  pub fn credits_due(&self) -> usize {
+    self.window.saturating_sub(self.outstanding)
+  }
+
+  /// The shortest time seen between a credit going out and a chunk arriving.
+  pub fn min_rtt(&self) -> Option<Duration> {
+    self.min_rtt
+  }
+
+  /// The smoothed rate at which chunks have been arriving, in chunks per second.
+  pub fn arrival_rate(&self) -> Option<f64> {
+    match self.interval {
+      Some(interval) if interval > 0.0 => Some(1.0 / interval),
+      _ => None
+    }
+  }
+
+  /// How long a credit may be out before it's written off.
+  pub fn rto(&self) -> Duration {
+    let rto = match self.min_rtt {
+      Some(rtt) => {
+        let millis = RTO_FACTOR * (seconds(rtt) + self.interval.unwrap_or(0.0)) * 1000.0;
+        cmp::max(millis as u64, MIN_RTO_MS)
+      },
+      None => INITIAL_RTO_MS
+    };
+    Duration::from_millis(rto) * (1 << self.backoff)
+  }
+
+  /// If nothing has gone out or come back for `rto()` as of `now`, writes off the credits that are
+  /// out, as their TOKENs or chunks must have been lost, and doubles the timeout. Returns how many
+  /// credits were written off.
+  pub fn expire(&mut self, now: Instant) -> usize {
+    let last_activity = match (self.issued.back(), self.last_arrival) {
+      (Some(&(issued, _)), Some(arrival)) => cmp::max(issued, arrival),
+      (Some(&(issued, _)), None) => issued,
+      (None, _) => { return 0; }
+    };
+    if now.duration_since(cmp::min(last_activity, now)) < self.rto() {
+      return 0;
+    }
+
+    let expired = self.outstanding;
+    self.outstanding = 0;
+    self.issued.clear();
+    self.backoff = cmp::min(self.backoff + 1, MAX_RTO_BACKOFF);
+    expired
+  }
+
+  /// Records that a credit went out at `now`.
+  pub fn on_issued(&mut self, now: Instant) {
+    self.outstanding += 1;
+    self.issued.push_back((now, None));
+  }
+
+  /// Records that a credit for the chunk at `offset` went out at `now`.
+  pub fn on_issued_for(&mut self, offset: usize, now: Instant) {
+    self.outstanding += 1;
+    self.issued.push_back((now, Some(offset)));
+  }
+
+  /// Records that a chunk arrived at `now`, using up the oldest credit, and resizes the window.
+  pub fn on_arrival(&mut self, now: Instant) {
+    self.outstanding = self.outstanding.saturating_sub(1);
+    let issued = self.issued.pop_front().map(|(issued, _)| issued);
+    self.arrived(issued, now);
+  }
+
+  /// Like `on_arrival()`, for the chunk at `offset`. The credits issued before its own on this
+  /// stream were lost, so they're written off, and their offsets are returned to be asked for
+  /// again. A chunk nobody is waiting for, e.g. a duplicate, uses up no credit.
+  pub fn on_arrival_of(&mut self, offset: usize, now: Instant) -> Vec<usize> {
+    let position = match self.issued.iter().position(|&(_, o)| o == Some(offset)) {
+      Some(position) => position,
+      None if self.issued.iter().any(|&(_, o)| o.is_some()) => { return vec![]; },
+      None => {
+        self.on_arrival(now);
+        return vec![];
+      }
+    };
+    let lost = self.issued.drain(..position).filter_map(|(_, o)| o).collect::<Vec<usize>>();
+    let issued = self.issued.pop_front().map(|(issued, _)| issued);
+    self.outstanding = self.outstanding.saturating_sub(position + 1);
+    self.arrived(issued, now);
+    lost
+  }
+
+  /// Takes a chunk arriving at `now` for a credit issued at `issued` into account.
+  fn arrived(&mut self, issued: Option<Instant>, now: Instant) {
+    self.backoff = 0;
+    if let Some(issued) = issued {
+      let rtt = now.duration_since(cmp::min(issued, now));
+      self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| cmp::min(min_rtt, rtt)));
+    }
+
+    if let Some(last_arrival) = self.last_arrival {
+      let sample = seconds(now.duration_since(cmp::min(last_arrival, now)));
+      self.interval = Some(self.interval.map_or(sample, |interval| interval + INTERVAL_SMOOTHING * (sample - interval)));
+    }
+    self.last_arrival = Some(now);
+    self.resize();
+  }
+
+  fn resize(&mut self) {
+    let (rate, rtt) = match (self.arrival_rate(), self.min_rtt) {
+      (Some(rate), Some(rtt)) => (rate, seconds(rtt)),
+      _ => { return; }
+    };
+    if rtt == 0.0 {
+      return;
+    }
+    let target = (2.0 * rate * rtt).ceil() as usize;
+    self.window = cmp::min(cmp::max(target, self.min), self.max);
+  }
+}
+
+fn seconds(duration: Duration) -> f64 {
+  duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
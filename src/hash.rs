@@ -0,0 +1,235 @@
+//! The hashes used to check that a blob arrived intact.
+//!
+//! The sender lists the algorithms it is willing to use in START or RESUME, most preferred first,
+//! and the receiver picks the first one it also has and names it in GOGO or RESUMED. Both ends
+//! hash the blob as it goes past, and END carries the sender's hash and the algorithm it used.
+//! Peers that don't negotiate hashes always use SHA-256.
+
+use sha2::{Digest, Sha256};
+
+pub const SHA256: &'static [u8] = b"sha256";
+/// xxHash64. Much cheaper than SHA-256, and good enough to catch corruption in transit, but not
+/// tampering.
+pub const XXH64: &'static [u8] = b"xxh64";
+
+/// Every algorithm this version of the crate supports, in the order senders offer them by
+/// default.
+pub const ALL: &'static [&'static [u8]] = &[SHA256, XXH64];
+
+pub trait BlobHasher {
+  /// The algorithm's name, as it travels in START, GOGO, RESUMED and END.
+  fn algorithm(&self) -> &'static [u8];
+
+  fn input(&mut self, bytes: &[u8]);
+
+  /// The hash of everything input so far, as lowercase hex. More bytes can be input afterwards.
+  fn hex_digest(&self) -> String;
+}
+
+/// Makes a hasher for `algorithm`, if we know it.
+pub fn new_hasher(algorithm: &[u8]) -> Option<Box<BlobHasher>> {
+  match algorithm {
+    b"sha256" => Some(Box::new(Sha256Hasher::new())),
+    b"xxh64" => Some(Box::new(Xxh64Hasher::new())),
+    _ => None
+  }
+}
+
+/// Picks the first of the `offered` algorithms that is also one of `ours`.
+pub fn choose(offered: &[Vec<u8>], ours: &[&'static [u8]]) -> Option<&'static [u8]> {
+  offered.iter().filter_map(|alg| ours.iter().find(|ours| **ours == alg.as_slice())).next().copied()
+}
+
+/// Turns the comma-separated list from START or RESUME into algorithm names.
+pub fn parse_list(frame: &[u8]) -> Vec<Vec<u8>> {
+  frame.split(|&b| b == b',').filter(|alg| !alg.is_empty()).map(|alg| alg.to_vec()).collect()
+}
+
+pub fn format_list(algorithms: &[Vec<u8>]) -> Vec<u8> {
+  algorithms.join(&b',')
+}
+
+pub struct Sha256Hasher {
+  inner: Sha256
+}
+
+impl Default for Sha256Hasher {
+  fn default() -> Sha256Hasher {
+    Sha256Hasher::new()
+  }
+}
+
+impl Sha256Hasher {
+  pub fn new() -> Sha256Hasher {
+    Sha256Hasher { inner: Sha256::new() }
+  }
+}
+
+impl BlobHasher for Sha256Hasher {
+  fn algorithm(&self) -> &'static [u8] {
+    SHA256
+  }
+
+  fn input(&mut self, bytes: &[u8]) {
+    self.inner.update(bytes);
+  }
+
+  fn hex_digest(&self) -> String {
+    self.inner.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect()
+  }
+}
+
+const PRIME64_1: u64 = 11400714785074694791;
+const PRIME64_2: u64 = 14029467366897019727;
+const PRIME64_3: u64 = 1609587929392839161;
+const PRIME64_4: u64 = 9650029242287828579;
+const PRIME64_5: u64 = 2870177450012600261;
+
+/// Streaming xxHash64 with a seed of 0. The digest is the 64-bit hash in big-endian hex, as the
+/// reference `xxhsum` prints it.
+pub struct Xxh64Hasher {
+  acc: [u64; 4],
+  buf: [u8; 32],
+  buf_len: usize,
+  total_len: u64
+}
+
+impl Default for Xxh64Hasher {
+  fn default() -> Xxh64Hasher {
+    Xxh64Hasher::new()
+  }
+}
+
+impl Xxh64Hasher {
+  pub fn new() -> Xxh64Hasher {
+    Xxh64Hasher {
+      acc: [
+        PRIME64_1.wrapping_add(PRIME64_2),
+        PRIME64_2,
+        0,
+        0u64.wrapping_sub(PRIME64_1)
+      ],
+      buf: [0; 32],
+      buf_len: 0,
+      total_len: 0
+    }
+  }
+
+  fn consume_stripe(acc: &mut [u64; 4], stripe: &[u8]) {
+    for (i, lane) in acc.iter_mut().enumerate() {
+      *lane = xxh64_round(*lane, read_u64_le(&stripe[i * 8..]));
+    }
+  }
+}
+
+impl BlobHasher for Xxh64Hasher {
+  fn algorithm(&self) -> &'static [u8] {
+    XXH64
+  }
+
+  fn input(&mut self, mut bytes: &[u8]) {
+    self.total_len += bytes.len() as u64;
+
+    if self.buf_len > 0 {
+      let wanted = 32 - self.buf_len;
+      if bytes.len() < wanted {
+        self.buf[self.buf_len..self.buf_len + bytes.len()].copy_from_slice(bytes);
+        self.buf_len += bytes.len();
+        return;
+      }
+      self.buf[self.buf_len..].copy_from_slice(&bytes[..wanted]);
+      let stripe = self.buf;
+      Xxh64Hasher::consume_stripe(&mut self.acc, &stripe);
+      self.buf_len = 0;
+      bytes = &bytes[wanted..];
+    }
+
+    while bytes.len() >= 32 {
+      Xxh64Hasher::consume_stripe(&mut self.acc, &bytes[..32]);
+      bytes = &bytes[32..];
+    }
+
+    self.buf[..bytes.len()].copy_from_slice(bytes);
+    self.buf_len = bytes.len();
+  }
+
+  fn hex_digest(&self) -> String {
+    let mut h = if self.total_len >= 32 {
+      let (v1, v2, v3, v4) = (self.acc[0], self.acc[1], self.acc[2], self.acc[3]);
+      let mut h = v1.rotate_left(1).wrapping_add(v2.rotate_left(7))
+                    .wrapping_add(v3.rotate_left(12)).wrapping_add(v4.rotate_left(18));
+      for &v in self.acc.iter() {
+        h = xxh64_merge_round(h, v);
+      }
+      h
+    } else {
+      PRIME64_5
+    };
+    h = h.wrapping_add(self.total_len);
+
+    let mut rest = &self.buf[..self.buf_len];
+    while rest.len() >= 8 {
+      h ^= xxh64_round(0, read_u64_le(rest));
+      h = h.rotate_left(27).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+      rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+      h ^= read_u32_le(rest).wrapping_mul(PRIME64_1);
+      h = h.rotate_left(23).wrapping_mul(PRIME64_2).wrapping_add(PRIME64_3);
+      rest = &rest[4..];
+    }
+    for &b in rest {
+      h ^= (b as u64).wrapping_mul(PRIME64_5);
+      h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^= h >> 32;
+    format!("{:016x}", h)
+  }
+}
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+  acc.wrapping_add(input.wrapping_mul(PRIME64_2)).rotate_left(31).wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge_round(acc: u64, val: u64) -> u64 {
+  (acc ^ xxh64_round(0, val)).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4)
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64 {
+  bytes[..8].iter().rev().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+fn read_u32_le(bytes: &[u8]) -> u64 {
+  bytes[..4].iter().rev().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+/// CRC-32C (Castagnoli), which each CHUNK carries when the peers agree to the `crc` capability,
+/// so that a chunk mangled in transit can be sent again on its own.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+  let crc = bytes.iter().fold(!0u32, |crc, &b| CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8));
+  !crc
+}
+
+/// The CRC-32C of every byte value, worked out once at compile time.
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+  let mut table = [0u32; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+}
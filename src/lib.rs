@@ -1,5 +1,4 @@
-#![feature(rustc_private, slice_patterns)]
-
+#[cfg(feature = "zmq")]
 extern crate zmq;
 
 #[macro_use]
@@ -8,36 +7,138 @@ extern crate log;
 use std::str;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::cmp;
-use std::time::{Duration, Instant};
-
-extern crate serialize;
-use serialize::hex::ToHex;
 
-extern crate rustc;
-use rustc::util::sha2::{Sha256, Digest};
+extern crate getrandom;
+extern crate sha2;
 
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug)]
-enum ErrorKind {
+pub enum ErrorKind {
+  #[cfg(feature = "zmq")]
   ZMQ_ERROR(zmq::Error),
+  IO_ERROR(io::ErrorKind),
   TIMEOUT,
   INVALID_RESPONSE,
   NOGO,
+  /// The receiver's hash of the blob didn't match ours, so it threw the blob away.
+  HASH_MISMATCH,
+  /// The receiver replied FAIL for some other reason, e.g. its sink couldn't commit the blob.
+  FAILED,
+  /// The send was cancelled through its `SendHandle`.
+  CANCELLED,
+  /// The receiver gave up on the blob partway through and told us why.
+  ABORTED(AbortReason),
+  /// The peer didn't agree to a capability this operation needs.
+  UNSUPPORTED,
+  /// The peer sent a message we didn't expect at this point in the transaction, or one with the
+  /// wrong number of frames. Usually means the peer speaks a different version of the protocol.
+  PROTOCOL_VIOLATION { expected: String, got: String, phase: &'static str },
 }
 
 impl fmt::Display for ErrorKind {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     let desc = match self.clone() {
+      #[cfg(feature = "zmq")]
       ErrorKind::ZMQ_ERROR(e) => e.description().to_owned(),
+      ErrorKind::IO_ERROR(k) => format!("IO_ERROR ({:?})", k),
       ErrorKind::TIMEOUT => "TIMEOUT".to_string(),
       ErrorKind::INVALID_RESPONSE => "INVALID_RESPONSE".to_string(),
-      ErrorKind::NOGO => "NOGO".to_string()
+      ErrorKind::NOGO => "NOGO".to_string(),
+      ErrorKind::HASH_MISMATCH => "HASH_MISMATCH".to_string(),
+      ErrorKind::FAILED => "FAILED".to_string(),
+      ErrorKind::CANCELLED => "CANCELLED".to_string(),
+      ErrorKind::ABORTED(reason) => format!("ABORTED ({:?})", reason),
+      ErrorKind::UNSUPPORTED => "UNSUPPORTED".to_string(),
+      ErrorKind::PROTOCOL_VIOLATION { expected, got, phase } => {
+        format!("PROTOCOL_VIOLATION during {}: expected {}, got {}", phase, expected, got)
+      }
     };
     write!(f, "{}", desc)
   }
 }
 
+/// Why one side of a transaction sent ABORT. Travels as the last frame of the ABORT message.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbortReason {
+  /// The sender's caller cancelled the send.
+  CANCELLED,
+  /// The receiver didn't hear from the sender within its TTL.
+  EXPIRED,
+  /// A message about the blob couldn't be parsed.
+  INVALID_REQUEST,
+  /// A chunk didn't fit in the blob, wasn't the size agreed, or the sink refused it.
+  CHUNK_REJECTED,
+  /// Something went wrong on the aborting side itself, e.g. a reply failed to send.
+  INTERNAL_ERROR,
+  /// A reason code this version doesn't know about.
+  UNKNOWN,
+}
+
+impl AbortReason {
+  pub fn code(&self) -> &'static [u8] {
+    match *self {
+      AbortReason::CANCELLED => b"CANCELLED",
+      AbortReason::EXPIRED => b"EXPIRED",
+      AbortReason::INVALID_REQUEST => b"INVALID_REQUEST",
+      AbortReason::CHUNK_REJECTED => b"CHUNK_REJECTED",
+      AbortReason::INTERNAL_ERROR => b"INTERNAL_ERROR",
+      AbortReason::UNKNOWN => b"UNKNOWN",
+    }
+  }
+
+  pub fn from_code(code: &[u8]) -> AbortReason {
+    match code {
+      b"CANCELLED" => AbortReason::CANCELLED,
+      b"EXPIRED" => AbortReason::EXPIRED,
+      b"INVALID_REQUEST" => AbortReason::INVALID_REQUEST,
+      b"CHUNK_REJECTED" => AbortReason::CHUNK_REJECTED,
+      b"INTERNAL_ERROR" => AbortReason::INTERNAL_ERROR,
+      _ => AbortReason::UNKNOWN,
+    }
+  }
+}
+
+/// Why the receiver threw a blob away after END. Travels as the last frame of the FAIL message.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailReason {
+  /// The receiver's hash of the blob didn't match the sender's.
+  HASH_MISMATCH,
+  /// END arrived before the whole blob had.
+  TRUNCATED,
+  /// Chunks were still missing when the receiver gave up waiting for them after END.
+  CHUNKS_MISSING,
+  /// The blob's sink couldn't commit it.
+  COMMIT_FAILED,
+  /// A reason code this version doesn't know about.
+  UNKNOWN,
+}
+
+impl FailReason {
+  pub fn code(&self) -> &'static [u8] {
+    match *self {
+      FailReason::HASH_MISMATCH => b"HASH_MISMATCH",
+      FailReason::TRUNCATED => b"TRUNCATED",
+      FailReason::CHUNKS_MISSING => b"CHUNKS_MISSING",
+      FailReason::COMMIT_FAILED => b"COMMIT_FAILED",
+      FailReason::UNKNOWN => b"UNKNOWN",
+    }
+  }
+
+  pub fn from_code(code: &[u8]) -> FailReason {
+    match code {
+      b"HASH_MISMATCH" => FailReason::HASH_MISMATCH,
+      b"TRUNCATED" => FailReason::TRUNCATED,
+      b"CHUNKS_MISSING" => FailReason::CHUNKS_MISSING,
+      b"COMMIT_FAILED" => FailReason::COMMIT_FAILED,
+      _ => FailReason::UNKNOWN,
+    }
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct XactError {
   kind: ErrorKind,
@@ -67,17 +168,139 @@ impl XactError {
     }
   }
 
-  fn from_zmq(e: zmq::Error, msg: &str) -> XactError {
-    XactError::new(ErrorKind::ZMQ_ERROR(e), msg)
+  /// Builds a PROTOCOL_VIOLATION error for a well-formed message that came at the wrong time.
+  fn protocol_violation(phase: &'static str, expected: &str, got: &protocol::Message) -> XactError {
+    let kind = ErrorKind::PROTOCOL_VIOLATION {
+      expected: expected.to_owned(),
+      got: String::from_utf8_lossy(got.command()).into_owned(),
+      phase: phase
+    };
+    XactError::new(kind, "Unexpected message from peer")
+  }
+
+  /// Says which part of the transaction a PROTOCOL_VIOLATION happened in. Other errors are
+  /// returned unchanged.
+  fn in_phase(self, phase: &'static str) -> XactError {
+    match self.kind {
+      ErrorKind::PROTOCOL_VIOLATION { expected, got, .. } => {
+        let kind = ErrorKind::PROTOCOL_VIOLATION { expected: expected, got: got, phase: phase };
+        XactError::new(kind, &self.msg)
+      },
+      _ => self
+    }
+  }
+
+  pub fn kind(&self) -> &ErrorKind {
+    &self.kind
+  }
+
+  pub fn msg(&self) -> &str {
+    &self.msg
   }
 }
 
+#[cfg(feature = "zmq")]
 impl From<zmq::Error> for XactError {
   fn from(e: zmq::Error) -> Self {
     XactError::new(ErrorKind::ZMQ_ERROR(e), e.description())
   }
 }
 
+impl From<io::Error> for XactError {
+  fn from(e: io::Error) -> Self {
+    XactError::new(ErrorKind::IO_ERROR(e.kind()), e.description())
+  }
+}
+
+/// Version of the wire protocol spoken by this crate. Sent in PING and PONG.
+///
+/// Version 2 carries the blob id in every message and frames START, RESUME, GOGO and RESUMED
+/// differently, so peers that speak an older version are refused: a receiver answers their PING
+/// with its own version and NOGOs their blobs, and a sender fails with UNSUPPORTED. That includes peers from before
+/// versions were negotiated, which send a bare PING or PONG.
+pub const PROTOCOL_VERSION: usize = 2;
+
+/// Optional protocol features. PING lists the ones the sender wants, PONG the ones both ends
+/// have.
+pub mod capabilities {
+  /// A sender can pick up a blob it didn't finish with RESUME. GOGO and RESUMED hand it a token
+  /// to prove the blob is its own, should it come back from another connection.
+  pub const RESUME: &'static [u8] = b"resume";
+  pub const CONS: &'static [u8] = b"cons";
+  pub const ABORT: &'static [u8] = b"abort";
+  /// START and RESUME offer hash algorithms, and GOGO, RESUMED and END name the one in use.
+  pub const HASHES: &'static [u8] = b"hashes";
+  /// CHUNK carries its offset and CRC-32C, the receiver asks for corrupted chunks again with
+  /// RESEND, and TOKEN says how much of the blob has been written.
+  pub const CRC: &'static [u8] = b"crc";
+  /// TOKEN names the offset of the chunk it wants, and the receiver takes chunks in whatever order
+  /// they arrive, asking again for the ones that went missing. Only used together with CRC.
+  pub const RANGES: &'static [u8] = b"ranges";
+  /// A sender can stripe one blob across several connections, by sending JOIN with the blob's
+  /// token on the extra ones. Only used together with RANGES.
+  pub const JOIN: &'static [u8] = b"join";
+  /// START and RESUME can say what chunk size the sender would like. The receiver still picks.
+  pub const CHUNK_SIZE: &'static [u8] = b"chunksize";
+
+  /// Everything this version of the crate supports.
+  pub const ALL: &'static [&'static [u8]] = &[RESUME, CONS, ABORT, HASHES, CRC, RANGES, JOIN, CHUNK_SIZE];
+}
+
+/// What was agreed with a peer during PING/PONG.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerInfo {
+  pub version: usize,
+  pub capabilities: Vec<Vec<u8>>
+}
+
+impl PeerInfo {
+  /// A peer that sent a bare PING or PONG, from before versions were negotiated. This crate
+  /// can't talk to one.
+  pub fn legacy() -> PeerInfo {
+    PeerInfo { version: 0, capabilities: vec![] }
+  }
+
+  /// Parses the frames following the PING or PONG command.
+  pub fn from_parts(parts: &[Vec<u8>]) -> Result<PeerInfo, XactError> {
+    match parts.split_first() {
+      None => Ok(PeerInfo::legacy()),
+      Some((version, capabilities)) => {
+        Ok(PeerInfo {
+          version: try!(bytes_to_int(version)),
+          capabilities: capabilities.to_vec()
+        })
+      }
+    }
+  }
+
+  /// Agrees on the lower of the two versions and the capabilities both ends have.
+  pub fn negotiate(&self, ours: &[&[u8]]) -> PeerInfo {
+    PeerInfo {
+      version: cmp::min(self.version, PROTOCOL_VERSION),
+      capabilities: self.capabilities.iter().filter(|c| ours.contains(&c.as_slice())).cloned().collect()
+    }
+  }
+
+  /// Whether the agreed version is one this crate speaks.
+  pub fn is_current(&self) -> bool {
+    self.version >= PROTOCOL_VERSION
+  }
+
+  pub fn supports(&self, capability: &[u8]) -> bool {
+    self.capabilities.iter().any(|c| c.as_slice() == capability)
+  }
+
+  /// The frames to put after the PING or PONG command. Empty for legacy peers.
+  pub fn to_parts(&self) -> Vec<Vec<u8>> {
+    if self.version == 0 {
+      return vec![];
+    }
+    let mut parts = vec![int_to_bytes(self.version)];
+    parts.extend(self.capabilities.iter().cloned());
+    parts
+  }
+}
+
 pub fn bytes_to_int(bytes: &[u8]) -> Result<usize, XactError> {
   let int_str = try!(str::from_utf8(bytes).map_err(|_| {
     XactError::new(ErrorKind::INVALID_RESPONSE, "Unable to parse bytes as utf-8")
@@ -94,5 +317,10 @@ pub fn int_to_bytes(num: usize) -> Vec<u8> {
   format!("{}", num).as_bytes().to_vec()
 }
 
+pub mod hash;
+pub mod flow;
+pub mod protocol;
+pub mod transport;
 pub mod sender;
 pub mod receiver;
+pub mod sink;
@@ -0,0 +1,385 @@
+//! The messages that senders and receivers exchange, and how they map to ZMQ multipart frames.
+//!
+//! Every message starts with a command frame, followed by a fixed number of argument frames
+//! (PING and PONG are the exception: their argument frames are the peer's version and
+//! capabilities, if any). Numbers travel as decimal ASCII and hashes as lowercase hex.
+//!
+//! Frames added by later capabilities go at the end, and are left off when talking to peers that
+//! didn't agree to the capability, so some messages have more than one valid frame count.
+//!
+//! The frames here don't include the ZMQ envelope. A sender's DEALER sends the frames as they
+//! are; the receiver's ROUTER sees them prefixed with the sender's identity, and replies with
+//! `[sender_id, "", frames...]`, so the sender sees its replies prefixed with an empty frame.
+
+use super::{bytes_to_int, int_to_bytes, AbortReason, ErrorKind, FailReason, PeerInfo, XactError};
+use super::hash;
+
+/// Sent as the last frame of every OK.
+const OK_MSG: &'static [u8] = b"Great success";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+  /// Sender to receiver: are you there, and what do you support?
+  Ping { peer: PeerInfo },
+  /// Receiver to sender: the version and capabilities both ends have.
+  Pong { peer: PeerInfo },
+  /// Sender to receiver: please accept a blob of `data_size` bytes. If `consistent` is set, the
+  /// receiver follows its OK with a CONS. `hash_algorithms` are the ones the sender will use, most
+  /// preferred first; if there are none, it's SHA-256. `chunk_size` is the chunk size the sender
+  /// would like, if it has a preference.
+  Start { blob_id: Vec<u8>, data_size: usize, consistent: bool, hash_algorithms: Vec<Vec<u8>>, chunk_size: Option<usize> },
+  /// Like START, but picks up a partial blob the receiver already has, if any. Only a blob that
+  /// was started from the same identity, or whose `token` from GOGO or RESUMED this is, gets
+  /// picked up.
+  Resume {
+    blob_id: Vec<u8>,
+    data_size: usize,
+    consistent: bool,
+    hash_algorithms: Vec<Vec<u8>>,
+    chunk_size: Option<usize>,
+    token: Option<Vec<u8>>
+  },
+  /// Receiver to sender: go ahead, in chunks of at most `chunk_size` bytes, hashing with
+  /// `hash_algorithm` (SHA-256 if None). `token` lets the sender RESUME the blob later.
+  Gogo { blob_id: Vec<u8>, chunk_size: usize, hash_algorithm: Option<Vec<u8>>, token: Option<Vec<u8>> },
+  /// Receiver to sender: the blob was refused.
+  Nogo { blob_id: Vec<u8> },
+  /// Receiver to sender: go ahead from `offset`. `prefix_hash` is the hex hash of the bytes
+  /// before it, with `hash_algorithm` (SHA-256 if None). `token` is as for GOGO.
+  Resumed {
+    blob_id: Vec<u8>,
+    chunk_size: usize,
+    offset: usize,
+    prefix_hash: Vec<u8>,
+    hash_algorithm: Option<Vec<u8>>,
+    token: Option<Vec<u8>>
+  },
+  /// Receiver to sender: send one more chunk. `written` is how much of the blob the receiver has
+  /// written so far; the sender can forget chunks before it. If `offset` is set, it's the chunk
+  /// starting there that's wanted rather than the next one.
+  Token { blob_id: Vec<u8>, written: Option<usize>, offset: Option<usize> },
+  /// Sender to receiver: the next `data.len()` bytes of the blob. `offset` says where they go
+  /// and `crc` is their CRC-32C.
+  Chunk { blob_id: Vec<u8>, data: Vec<u8>, offset: Option<usize>, crc: Option<u32> },
+  /// Receiver to sender: the chunk at `offset` arrived corrupted or too early, so send it again.
+  Resend { blob_id: Vec<u8>, offset: usize },
+  /// Sender to receiver: that was everything. `hash` is the hex hash of the whole blob, with
+  /// `hash_algorithm` (SHA-256 if None).
+  End { blob_id: Vec<u8>, hash: Vec<u8>, hash_algorithm: Option<Vec<u8>> },
+  /// Receiver to sender: the blob arrived intact and was committed.
+  Ok { blob_id: Vec<u8> },
+  /// Receiver to sender: the blob was thrown away after END, and why.
+  Fail { blob_id: Vec<u8>, reason: FailReason },
+  /// Receiver to sender: the result of a consistent transaction.
+  Cons { blob_id: Vec<u8>, result: Vec<u8> },
+  /// Either direction: stop sending this blob.
+  Abort { blob_id: Vec<u8>, reason: AbortReason },
+  /// Sender to receiver, on another connection: send TOKENs for this blob, which is already
+  /// being sent, here as well. `token` is the one from GOGO or RESUMED. Answered with TOKENs, or
+  /// NOGO.
+  Join { blob_id: Vec<u8>, token: Vec<u8> },
+}
+
+impl Message {
+  pub fn command(&self) -> &'static [u8] {
+    match *self {
+      Message::Ping { .. } => b"PING",
+      Message::Pong { .. } => b"PONG",
+      Message::Start { .. } => b"START",
+      Message::Resume { .. } => b"RESUME",
+      Message::Gogo { .. } => b"GOGO",
+      Message::Nogo { .. } => b"NOGO",
+      Message::Resumed { .. } => b"RESUMED",
+      Message::Token { .. } => b"TOKEN",
+      Message::Chunk { .. } => b"CHUNK",
+      Message::Resend { .. } => b"RESEND",
+      Message::End { .. } => b"END",
+      Message::Ok { .. } => b"OK",
+      Message::Fail { .. } => b"FAIL",
+      Message::Cons { .. } => b"CONS",
+      Message::Abort { .. } => b"ABORT",
+      Message::Join { .. } => b"JOIN",
+    }
+  }
+
+  /// The blob this message is about. None for PING and PONG.
+  pub fn blob_id(&self) -> Option<&[u8]> {
+    match *self {
+      Message::Ping { .. } | Message::Pong { .. } => None,
+      Message::Start { ref blob_id, .. } |
+      Message::Resume { ref blob_id, .. } |
+      Message::Gogo { ref blob_id, .. } |
+      Message::Nogo { ref blob_id } |
+      Message::Resumed { ref blob_id, .. } |
+      Message::Token { ref blob_id, .. } |
+      Message::Chunk { ref blob_id, .. } |
+      Message::Resend { ref blob_id, .. } |
+      Message::End { ref blob_id, .. } |
+      Message::Ok { ref blob_id } |
+      Message::Fail { ref blob_id, .. } |
+      Message::Cons { ref blob_id, .. } |
+      Message::Abort { ref blob_id, .. } |
+      Message::Join { ref blob_id, .. } => Some(blob_id)
+    }
+  }
+
+  /// Turns the message into frames, starting with the command. Consumes the message so that
+  /// chunk data doesn't have to be copied.
+  pub fn encode(self) -> Vec<Vec<u8>> {
+    let mut frames = vec![self.command().to_vec()];
+    match self {
+      Message::Ping { peer } | Message::Pong { peer } => {
+        frames.extend(peer.to_parts());
+      },
+      Message::Start { blob_id, data_size, consistent, hash_algorithms, chunk_size } => {
+        frames.push(blob_id);
+        frames.push(int_to_bytes(data_size));
+        push_trailing(&mut frames, vec![hash_list(&hash_algorithms), chunk_size.map(int_to_bytes), consistency_flag(consistent)]);
+      },
+      Message::Resume { blob_id, data_size, consistent, hash_algorithms, chunk_size, token } => {
+        frames.push(blob_id);
+        frames.push(int_to_bytes(data_size));
+        push_trailing(&mut frames, vec![
+          hash_list(&hash_algorithms),
+          chunk_size.map(int_to_bytes),
+          consistency_flag(consistent),
+          token
+        ]);
+      },
+      Message::Gogo { blob_id, chunk_size, hash_algorithm, token } => {
+        frames.push(blob_id);
+        frames.push(int_to_bytes(chunk_size));
+        push_trailing(&mut frames, vec![hash_algorithm, token]);
+      },
+      Message::Nogo { blob_id } => {
+        frames.push(blob_id);
+        frames.push(b"0".to_vec());
+      },
+      Message::Join { blob_id, token } => {
+        frames.push(blob_id);
+        frames.push(token);
+      },
+      Message::Resumed { blob_id, chunk_size, offset, prefix_hash, hash_algorithm, token } => {
+        frames.push(blob_id);
+        frames.push(int_to_bytes(chunk_size));
+        frames.push(int_to_bytes(offset));
+        frames.push(prefix_hash);
+        push_trailing(&mut frames, vec![hash_algorithm, token]);
+      },
+      Message::Token { blob_id, written, offset } => {
+        frames.push(blob_id);
+        if let Some(written) = written {
+          frames.push(int_to_bytes(written));
+          frames.extend(offset.map(int_to_bytes));
+        }
+      },
+      Message::Chunk { blob_id, data, offset, crc } => {
+        frames.push(blob_id);
+        frames.push(data);
+        if let (Some(offset), Some(crc)) = (offset, crc) {
+          frames.push(int_to_bytes(offset));
+          frames.push(int_to_bytes(crc as usize));
+        }
+      },
+      Message::Resend { blob_id, offset } => {
+        frames.push(blob_id);
+        frames.push(int_to_bytes(offset));
+      },
+      Message::End { blob_id, hash, hash_algorithm } => {
+        frames.push(blob_id);
+        frames.push(hash);
+        frames.extend(hash_algorithm);
+      },
+      Message::Ok { blob_id } => {
+        frames.push(blob_id);
+        frames.push(OK_MSG.to_vec());
+      },
+      Message::Fail { blob_id, reason } => {
+        frames.push(blob_id);
+        frames.push(reason.code().to_vec());
+      },
+      Message::Cons { blob_id, result } => {
+        frames.push(blob_id);
+        frames.push(result);
+      },
+      Message::Abort { blob_id, reason } => {
+        frames.push(blob_id);
+        frames.push(reason.code().to_vec());
+      }
+    }
+    frames
+  }
+
+  /// Parses frames starting with the command. Fails with PROTOCOL_VIOLATION if the command is
+  /// unknown or has the wrong number of frames, and INVALID_RESPONSE if a number doesn't parse.
+  pub fn decode(frames: Vec<Vec<u8>>) -> Result<Message, XactError> {
+    let num_frames = frames.len();
+    let mut frames = frames.into_iter();
+    let command = match frames.next() {
+      Some(command) => command,
+      None => { return Err(malformed("a command", "an empty message".to_owned())); }
+    };
+    let mut args: Vec<Vec<u8>> = frames.collect();
+
+    // The frame counts each command can have, fewest first.
+    let expected_frames: Option<&[usize]> = match command.as_slice() {
+      b"PING" | b"PONG" => None,
+      b"JOIN" => Some(&[3]),
+      b"TOKEN" => Some(&[2, 3, 4]),
+      b"NOGO" | b"OK" | b"FAIL" | b"CONS" | b"ABORT" | b"RESEND" => Some(&[3]),
+      b"CHUNK" => Some(&[3, 5]),
+      b"END" => Some(&[3, 4]),
+      b"GOGO" => Some(&[3, 4, 5]),
+      b"START" => Some(&[3, 4, 5, 6]),
+      b"RESUME" => Some(&[3, 4, 5, 6, 7]),
+      b"RESUMED" => Some(&[5, 6, 7]),
+      _ => { return Err(malformed("a known command", describe(&command, num_frames))); }
+    };
+    if let Some(counts) = expected_frames {
+      if !counts.contains(&num_frames) {
+        let expected = match (counts.first(), counts.last()) {
+          (Some(&fewest), _) if num_frames < fewest => describe(&command, fewest),
+          (_, Some(&most)) if num_frames > most => describe(&command, most),
+          _ => format!("{} ({:?} frames)", String::from_utf8_lossy(&command), counts)
+        };
+        return Err(malformed(&expected, describe(&command, num_frames)));
+      }
+    }
+
+    match command.as_slice() {
+      b"PING" => { return Ok(Message::Ping { peer: try!(PeerInfo::from_parts(&args)) }); },
+      b"PONG" => { return Ok(Message::Pong { peer: try!(PeerInfo::from_parts(&args)) }); },
+      _ => {}
+    }
+
+    // The frame count has been checked, so there are exactly enough of these, followed by any
+    // frames that later capabilities added at the end.
+    let min_frames = expected_frames.and_then(|counts| counts.first().cloned()).unwrap_or(1);
+    let mut extra = args.split_off(min_frames - 1).into_iter();
+    let mut optional = || extra.next();
+    let mut args = args.into_iter();
+    let mut next = || args.next().unwrap();
+    let msg = match command.as_slice() {
+      b"START" => {
+        let (blob_id, data_size) = (next(), try!(bytes_to_int(&next())));
+        let hash_algorithms = optional().map_or(vec![], |list| hash::parse_list(&list));
+        let chunk_size = try!(optional_int(present(optional())));
+        Message::Start {
+          blob_id: blob_id,
+          data_size: data_size,
+          consistent: optional().map_or(false, |flag| flag == b"1"),
+          hash_algorithms: hash_algorithms,
+          chunk_size: chunk_size
+        }
+      },
+      b"RESUME" => {
+        let (blob_id, data_size) = (next(), try!(bytes_to_int(&next())));
+        let hash_algorithms = optional().map_or(vec![], |list| hash::parse_list(&list));
+        let chunk_size = try!(optional_int(present(optional())));
+        Message::Resume {
+          blob_id: blob_id,
+          data_size: data_size,
+          consistent: optional().map_or(false, |flag| flag == b"1"),
+          hash_algorithms: hash_algorithms,
+          chunk_size: chunk_size,
+          token: optional()
+        }
+      },
+      b"GOGO" => Message::Gogo {
+        blob_id: next(),
+        chunk_size: try!(bytes_to_int(&next())),
+        hash_algorithm: present(optional()),
+        token: optional()
+      },
+      b"NOGO" => Message::Nogo { blob_id: next() },
+      b"RESUMED" => Message::Resumed {
+        blob_id: next(),
+        chunk_size: try!(bytes_to_int(&next())),
+        offset: try!(bytes_to_int(&next())),
+        prefix_hash: next(),
+        hash_algorithm: present(optional()),
+        token: optional()
+      },
+      b"TOKEN" => Message::Token {
+        blob_id: next(),
+        written: try!(optional_int(optional())),
+        offset: try!(optional_int(optional()))
+      },
+      b"CHUNK" => Message::Chunk {
+        blob_id: next(),
+        data: next(),
+        offset: try!(optional_int(optional())),
+        crc: try!(optional_crc(optional()))
+      },
+      b"RESEND" => Message::Resend { blob_id: next(), offset: try!(bytes_to_int(&next())) },
+      b"END" => Message::End { blob_id: next(), hash: next(), hash_algorithm: optional() },
+      b"OK" => Message::Ok { blob_id: next() },
+      b"FAIL" => Message::Fail { blob_id: next(), reason: FailReason::from_code(&next()) },
+      b"CONS" => Message::Cons { blob_id: next(), result: next() },
+      b"ABORT" => Message::Abort { blob_id: next(), reason: AbortReason::from_code(&next()) },
+      b"JOIN" => Message::Join { blob_id: next(), token: next() },
+      _ => unreachable!()
+    };
+    Ok(msg)
+  }
+}
+
+/// Finds the blob id in a message that may not decode, so that the transaction it belongs to
+/// can be aborted.
+pub fn blob_id_of(frames: &[Vec<u8>]) -> Option<&[u8]> {
+  match frames.first().map(|command| command.as_slice()) {
+    None | Some(b"PING") | Some(b"PONG") => None,
+    Some(_) => frames.get(1).map(|blob_id| blob_id.as_slice())
+  }
+}
+
+/// Pushes the frames that later capabilities added, in order. A missing one is sent empty if
+/// there are more after it, to keep their places, and left off otherwise.
+fn push_trailing(frames: &mut Vec<Vec<u8>>, trailing: Vec<Option<Vec<u8>>>) {
+  let count = trailing.iter().rposition(|frame| frame.is_some()).map_or(0, |last| last + 1);
+  frames.extend(trailing.into_iter().take(count).map(|frame| frame.unwrap_or_default()));
+}
+
+/// Treats an optional frame that was sent empty, to make room for the ones after it, as absent.
+fn present(frame: Option<Vec<u8>>) -> Option<Vec<u8>> {
+  frame.and_then(|frame| if frame.is_empty() { None } else { Some(frame) })
+}
+
+fn optional_int(frame: Option<Vec<u8>>) -> Result<Option<usize>, XactError> {
+  match frame {
+    Some(frame) => bytes_to_int(&frame).map(Some),
+    None => Ok(None)
+  }
+}
+
+/// A CRC-32C frame, which has to fit in 32 bits.
+fn optional_crc(frame: Option<Vec<u8>>) -> Result<Option<u32>, XactError> {
+  match try!(optional_int(frame)) {
+    Some(crc) if crc > u32::MAX as usize => Err(XactError::new(ErrorKind::INVALID_RESPONSE, "CRC is over 32 bits")),
+    crc => Ok(crc.map(|crc| crc as u32))
+  }
+}
+
+/// The hash list for START or RESUME. Left off if there's nothing to offer.
+fn hash_list(hash_algorithms: &[Vec<u8>]) -> Option<Vec<u8>> {
+  if hash_algorithms.is_empty() { None } else { Some(hash::format_list(hash_algorithms)) }
+}
+
+/// The consistency flag for START or RESUME. Only a consistent transaction needs one, and only a
+/// receiver that agreed to CONS can take part in one, so nobody else is sent it.
+fn consistency_flag(consistent: bool) -> Option<Vec<u8>> {
+  if consistent { Some(b"1".to_vec()) } else { None }
+}
+
+fn describe(command: &[u8], num_frames: usize) -> String {
+  format!("{} ({} frames)", String::from_utf8_lossy(command), num_frames)
+}
+
+fn malformed(expected: &str, got: String) -> XactError {
+  let kind = ErrorKind::PROTOCOL_VIOLATION {
+    expected: expected.to_owned(),
+    got: got,
+    phase: "decode"
+  };
+  XactError::new(kind, "Malformed message from peer")
+}
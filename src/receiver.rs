@@ -1,73 +1,399 @@
-use zmq;
-
 use std::str;
-use std::error::Error;
 use std::fmt;
 use std::cmp;
 use std::time::{Duration, Instant};
 
-use serialize::hex::ToHex;
-use rustc::util::sha2::{Sha256, Digest};
-
-use super::{bytes_to_int, ErrorKind, int_to_bytes, XactError};
+use super::{capabilities, AbortReason, FailReason, PeerInfo, XactError, PROTOCOL_VERSION};
+use super::flow::CreditWindow;
+use super::hash::{self, BlobHasher};
+use super::protocol::{self, Message};
+use super::sink::BlobSink;
+use super::transport::Transport;
+#[cfg(feature = "zmq")]
+use super::transport::ZmqTransport;
 
-use std::thread;
 use std::sync::mpsc::{channel, SendError};
 use std::sync::mpsc::Receiver as ChannelReceiver;
 use std::sync::mpsc::Sender as ChannelSender;
-use std::collections::HashMap;
-use std::marker::{Send, Sized};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
-const BLOB_TTL_SECONDS: u64 = 10;
+/// How long a blob is kept after its sender goes quiet, by default.
+pub const DEFAULT_BLOB_TTL_SECONDS: u64 = 10;
+/// How long a blob is kept after its sender goes quiet by default, if the sender can come back
+/// for it with RESUME or it is being written to a sink.
+pub const DEFAULT_RESUMABLE_TTL_SECONDS: u64 = 600;
+/// How long an END that arrived before all the chunks waits for the rest, by default.
+pub const DEFAULT_END_WAIT_SECONDS: u64 = 5;
 pub const DEFAULT_CHUNK_SIZE: usize = 1e7 as usize;
-const MAX_SIMUL_CHUNKS: usize = 10;
+/// The smallest chunk size a sender can talk us into by default, unless `chunk_size` is smaller.
+pub const DEFAULT_MIN_CHUNK_SIZE: usize = 4096;
+/// How much memory the chunks in flight from one sender may take up between them, by default.
+pub const DEFAULT_CHUNK_MEMORY: usize = 1 << 30;
+/// How many bytes of blobs may be buffered in memory at once by default, for all senders.
+pub const DEFAULT_MEMORY_BUDGET: usize = 1 << 31;
+/// The biggest blob that is buffered in memory by default.
+pub const DEFAULT_MAX_BLOB_MEMORY: usize = 1 << 30;
+/// How many bytes of blobs one sender may have buffered in memory at once by default.
+pub const DEFAULT_MAX_SENDER_MEMORY: usize = 1 << 30;
+/// How many chunks each stream may have in flight before anything has been measured.
+const INITIAL_WINDOW: usize = 10;
+pub const DEFAULT_MIN_WINDOW: usize = 2;
+pub const DEFAULT_MAX_WINDOW: usize = 64;
 const MSG_PADDING: usize = 100;
+/// How long a reply may wait for room on the transport before it is given up on.
+const REPLY_TIMEOUT_MS: u64 = 1000;
+const MAX_TRACKED_PEERS: usize = 1024;
+/// How many connections a blob can be striped across by default, counting the one that
+/// started it.
+pub const DEFAULT_MAX_STREAMS: usize = 8;
 pub const STOP: bool = true;
 
 pub struct Blob {
   pub id: Vec<u8>,
+  /// The bytes received so far, if the blob has no sink. Grows as chunks are written.
   pub array: Vec<u8>,
   pub index: usize,
-  pub hash: Sha256,
-  time_to_die: Instant
+  pub hash: Box<BlobHasher>,
+  pub data_size: usize,
+  pub consistent: bool,
+  sink: Option<Box<BlobSink>>,
+  last_activity: Instant,
+  ttl: Duration,
+  /// Handed to the sender in GOGO or RESUMED, so that only it can pick the blob up again.
+  token: Vec<u8>,
+  /// The hash and algorithm from an END that arrived while chunks were still being resent, and
+  /// when to stop waiting for them.
+  pending_end: Option<(Vec<u8>, Option<Vec<u8>>, Instant)>,
+  /// The offset last asked for with RESEND, when chunks have to be written in order.
+  resent: Option<usize>,
+  /// The chunk size agreed in GOGO or RESUMED, or 0 if there wasn't one.
+  agreed_chunk_size: usize,
+  /// When the sender agreed to RANGES, which chunks have arrived, one bit per `chunk_size` bytes
+  /// from `ranges_start`. Chunk size is 0 otherwise.
+  received: Vec<u64>,
+  ranges_start: usize,
+  chunk_size: usize,
+  next_request: usize,
+  /// Chunks that arrived ahead of `index`, by offset, waiting for the ones before them.
+  early: BTreeMap<usize, Vec<u8>>
 }
 
 impl Blob {
   pub fn new(id: &[u8], array_size: usize) -> Blob {
-    let mut hash = Sha256::new();
-    let mut array = vec![0; array_size];
     Blob {
       id: id.to_vec(),
-      array: array,
+      array: vec![],
+      index: 0,
+      hash: Box::new(hash::Sha256Hasher::new()),
+      data_size: array_size,
+      consistent: false,
+      sink: None,
+      last_activity: Instant::now(),
+      ttl: Duration::from_secs(DEFAULT_BLOB_TTL_SECONDS),
+      token: new_token(),
+      pending_end: None,
+      resent: None,
+      agreed_chunk_size: 0,
+      received: vec![],
+      ranges_start: 0,
+      chunk_size: 0,
+      next_request: 0,
+      early: BTreeMap::new()
+    }
+  }
+
+  /// Creates a blob whose chunks are streamed into `sink` rather than buffered in `array`.
+  pub fn with_sink(id: &[u8], data_size: usize, sink: Box<BlobSink>) -> Blob {
+    Blob {
+      id: id.to_vec(),
+      array: vec![],
       index: 0,
-      hash: hash,
-      time_to_die: Blob::get_next_ttl()
+      hash: Box::new(hash::Sha256Hasher::new()),
+      data_size: data_size,
+      consistent: false,
+      sink: Some(sink),
+      last_activity: Instant::now(),
+      ttl: Duration::from_secs(DEFAULT_BLOB_TTL_SECONDS),
+      token: new_token(),
+      pending_end: None,
+      resent: None,
+      agreed_chunk_size: 0,
+      received: vec![],
+      ranges_start: 0,
+      chunk_size: 0,
+      next_request: 0,
+      early: BTreeMap::new()
     }
   }
 
   pub fn is_alive(&self) -> bool {
-    Instant::now() < self.time_to_die
+    self.is_alive_at(Instant::now())
+  }
+
+  /// Whether we've heard about the blob within its TTL of `now`.
+  pub fn is_alive_at(&self, now: Instant) -> bool {
+    now < self.last_activity + self.ttl
+  }
+
+  /// The blob's transfer token. Random, so only the sender it was handed to knows it.
+  pub fn token(&self) -> &[u8] {
+    &self.token
+  }
+
+  /// How long the blob is kept once its sender goes quiet. Defaults to
+  /// `DEFAULT_BLOB_TTL_SECONDS`.
+  pub fn set_ttl(&mut self, ttl: Duration) {
+    self.ttl = ttl;
   }
 
   pub fn update_ttl(&mut self) {
-    self.time_to_die = Blob::get_next_ttl();
+    self.touch(Instant::now());
   }
 
-  fn get_next_ttl() -> Instant {
-    Instant::now() + Duration::from_secs(BLOB_TTL_SECONDS)
+  /// Records that we heard about the blob at `now`.
+  pub fn touch(&mut self, now: Instant) {
+    self.last_activity = now;
   }
 
-  pub fn get_next_chunk(&mut self, chunk_size: usize) -> &mut [u8] {
-    &mut self.array[self.index..self.index + chunk_size]
+  /// When we last heard about the blob.
+  pub fn last_activity(&self) -> Instant {
+    self.last_activity
   }
 
-  pub fn consume(&mut self, bytes: &[u8]) {
-    let start = Instant::now();
-    self.array.extend_from_slice(&bytes);
+  pub fn is_complete(&self) -> bool {
+    self.index == self.data_size
+  }
 
+  /// Hands a received chunk to the sink, or copies it into `array` if there is no sink.
+  /// Returns false if the chunk doesn't fit in the blob or the sink refused it.
+  pub fn write_chunk(&mut self, bytes: &[u8]) -> bool {
+    if bytes.len() > self.data_size - self.index {
+      return false;
+    }
 
-    self.hash.input(&bytes);
-    self.update_ttl();
+    let offset = self.index;
+    let accepted = match self.sink {
+      Some(ref mut sink) => sink.on_chunk(offset, bytes),
+      None => {
+        let needed = offset + bytes.len();
+        if needed > self.array.capacity() {
+          // Grow the usual way, but never past the end of the blob.
+          let capacity = cmp::min(cmp::max(self.array.capacity() * 2, needed), self.data_size);
+          let additional = capacity - self.array.len();
+          self.array.reserve_exact(additional);
+        }
+        self.array.truncate(offset);
+        self.array.extend_from_slice(bytes);
+        true
+      }
+    };
+    if !accepted {
+      return false;
+    }
+
+    self.hash.input(bytes);
+    self.index += bytes.len();
+    true
+  }
+
+  /// Expects every chunk from now on to be `chunk_size` bytes, except the last, which gets
+  /// whatever is left of the blob.
+  pub fn expect_chunks(&mut self, chunk_size: usize) {
+    self.agreed_chunk_size = chunk_size;
+  }
+
+  /// How many bytes the chunk at `offset` should have, if a chunk size was agreed.
+  pub fn expected_chunk_len(&self, offset: usize) -> Option<usize> {
+    if self.agreed_chunk_size == 0 {
+      return None;
+    }
+    Some(cmp::min(self.agreed_chunk_size, self.data_size.saturating_sub(offset)))
+  }
+
+  /// Starts keeping track of which chunks of `chunk_size` bytes have arrived, counting from
+  /// `index`, so that they can be taken in any order. A `chunk_size` of 0 stops tracking, and
+  /// chunks have to be written in order again.
+  pub fn track_ranges(&mut self, chunk_size: usize) {
+    let num_chunks = (self.data_size - self.index + chunk_size).saturating_sub(1).checked_div(chunk_size).unwrap_or(0);
+    self.received = vec![0; (num_chunks + 63) / 64];
+    self.ranges_start = self.index;
+    self.chunk_size = chunk_size;
+    self.next_request = self.index;
+    self.early.clear();
+  }
+
+  /// Which chunk starts at `offset`, if any chunk does.
+  fn chunk_number(&self, offset: usize) -> Option<usize> {
+    if self.chunk_size == 0 || offset < self.ranges_start || offset >= self.data_size {
+      return None;
+    }
+    let distance = offset - self.ranges_start;
+    if distance % self.chunk_size == 0 { Some(distance / self.chunk_size) } else { None }
+  }
+
+  /// Whether the chunk at `offset` has already arrived, written or not.
+  pub fn has_chunk(&self, offset: usize) -> bool {
+    offset < self.index || self.chunk_number(offset).map_or(false, |n| self.received[n / 64] & (1 << (n % 64)) != 0)
+  }
+
+  /// Takes the chunk that starts at `offset`, writing it and any early chunks behind it if it's
+  /// next, and keeping it until it is otherwise. Returns false if no chunk of that size starts
+  /// there, or the sink refused a chunk.
+  pub fn receive_chunk(&mut self, offset: usize, bytes: Vec<u8>) -> bool {
+    let n = match self.chunk_number(offset) {
+      Some(n) if bytes.len() == cmp::min(self.chunk_size, self.data_size - offset) => n,
+      _ => { return false; }
+    };
+    if self.has_chunk(offset) {
+      return true;
+    }
+    self.received[n / 64] |= 1 << (n % 64);
+
+    if offset != self.index {
+      self.early.insert(offset, bytes);
+      return true;
+    }
+    if !self.write_chunk(&bytes) {
+      return false;
+    }
+    while let Some(bytes) = self.early.remove(&self.index) {
+      if !self.write_chunk(&bytes) {
+        return false;
+      }
+    }
+    true
+  }
+
+  /// The offset of the next chunk to ask for, if any are left before `limit`.
+  fn next_request(&mut self, limit: usize) -> Option<usize> {
+    if self.chunk_size == 0 || self.next_request >= cmp::min(self.data_size, limit) {
+      return None;
+    }
+    let offset = self.next_request;
+    self.next_request += self.chunk_size;
+    Some(offset)
+  }
+
+  /// The offsets of chunks that have been asked for but haven't arrived.
+  pub fn missing_chunks(&self) -> Vec<usize> {
+    let mut missing = vec![];
+    let mut offset = self.ranges_start;
+    while self.chunk_size > 0 && offset < self.next_request {
+      if !self.has_chunk(offset) {
+        missing.push(offset);
+      }
+      offset += self.chunk_size;
+    }
+    missing
+  }
+
+  /// Tells the sink, if any, that the blob is done. Returns whether the blob was committed.
+  pub fn finish(&mut self, hash_ok: bool) -> bool {
+    match self.sink {
+      Some(ref mut sink) => sink.on_finish(hash_ok),
+      None => hash_ok
+    }
+  }
+
+  /// Tells the sink, if any, that the blob is being thrown away before completion.
+  pub fn abort(&mut self) {
+    if let Some(ref mut sink) = self.sink {
+      sink.on_abort();
+    }
+  }
+
+  /// How much memory the blob can take up: all of it if it's buffered in `array`, which leaves
+  /// room for the early chunks too, and just the early chunks if it goes to a sink.
+  pub fn memory_needed(&self) -> usize {
+    let early = self.early.values().map(Vec::len).sum();
+    if self.sink.is_some() { early } else { self.data_size }
+  }
+}
+
+/// Something that happened inside a `BlobReceiver`. The `Display` form of each event is the
+/// message that gets passed to `BlobReceiverBehavior::on_info()`.
+#[derive(Debug)]
+pub enum ReceiverEvent<'e> {
+  ShuttingDown,
+  BlobCreated { blob_id: &'e [u8], data_size: usize },
+  BlobResumed { blob_id: &'e [u8], offset: usize },
+  /// Another connection joined in sending the blob. `streams` counts them all, including the one
+  /// that started it.
+  StreamJoined { blob_id: &'e [u8], streams: usize },
+  /// A connection asked to join a blob that isn't being sent by offset, or has enough streams.
+  JoinRefused { blob_id: &'e [u8] },
+  NotReady { blob_id: &'e [u8] },
+  /// Buffering the blob would go over one of the receiver's memory limits.
+  OverBudget { blob_id: &'e [u8], data_size: usize },
+  SinkRefused { blob_id: &'e [u8] },
+  /// None of the hash algorithms the sender offered are in `ReceiverMachine::hash_algorithms`.
+  NoCommonHash { blob_id: &'e [u8] },
+  /// `reply` is the command we were trying to send.
+  ReplyFailed { blob_id: &'e [u8], reply: &'static str, error: XactError },
+  /// `elapsed` is the time since the previous message about the blob.
+  ChunkReceived { blob_id: &'e [u8], size: usize, elapsed: Duration },
+  ChunkRejected { blob_id: &'e [u8] },
+  /// The chunk at `offset` had `size` bytes rather than the `expected` number.
+  ChunkWrongSize { blob_id: &'e [u8], offset: usize, size: usize, expected: usize },
+  /// The chunk at `offset` failed its CRC check, so we asked for it again, with RESEND or a TOKEN
+  /// for its offset.
+  ChunkCorrupted { blob_id: &'e [u8], offset: usize },
+  ChunkAppended { blob_id: &'e [u8], bytes_received: usize, data_size: usize },
+  ChunkRequested { blob_id: &'e [u8] },
+  UnknownBlob { blob_id: &'e [u8] },
+  CheckingHash { blob_id: &'e [u8] },
+  HashMismatch { blob_id: &'e [u8] },
+  /// END arrived before the whole blob did, from a sender that can't be asked for the rest.
+  BlobTruncated { blob_id: &'e [u8], bytes_received: usize, data_size: usize },
+  CommitFailed { blob_id: &'e [u8] },
+  Completed { blob_id: &'e [u8], data_size: usize },
+  /// The sender gave up on the blob.
+  BlobAborted { blob_id: &'e [u8], reason: AbortReason },
+  /// We gave up on the blob because the sender went quiet for too long.
+  BlobExpired { blob_id: &'e [u8] },
+  /// A sender PINGed us with an older protocol version than ours. 0 means a bare PING. It's told
+  /// our version in the PONG, and any blob it STARTs or RESUMEs anyway gets a NOGO.
+  PeerTooOld { version: usize },
+}
+
+impl<'e> fmt::Display for ReceiverEvent<'e> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ReceiverEvent::ShuttingDown => write!(f, "Received shutdown signal. Exiting."),
+      ReceiverEvent::BlobCreated { .. } => write!(f, "Created new blob."),
+      ReceiverEvent::BlobResumed { .. } => write!(f, "Resuming blob."),
+      ReceiverEvent::StreamJoined { streams, .. } => write!(f, "Stream joined blob. Now {} streams.", streams),
+      ReceiverEvent::JoinRefused { .. } => write!(f, "Can't join blob. NOGO sent."),
+      ReceiverEvent::NotReady { .. } => write!(f, "Not ready. NOGO sent."),
+      ReceiverEvent::OverBudget { data_size, .. } => write!(f, "No memory for {} more bytes. NOGO sent.", data_size),
+      ReceiverEvent::SinkRefused { .. } => write!(f, "Sink refused blob. NOGO sent."),
+      ReceiverEvent::NoCommonHash { .. } => write!(f, "No hash algorithm in common. NOGO sent."),
+      ReceiverEvent::ReplyFailed { reply, ref error, .. } => {
+        write!(f, "Error sending {} message: {}. Aborting transaction.", reply, error)
+      },
+      ReceiverEvent::ChunkReceived { size, elapsed, .. } => {
+        let ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() as f64 / 1e6) as u64;
+        write!(f, "Received {} bytes in {} ms.", size, ms)
+      },
+      ReceiverEvent::ChunkRejected { .. } => write!(f, "Unable to write chunk. Aborting transaction."),
+      ReceiverEvent::ChunkWrongSize { offset, size, expected, .. } => {
+        write!(f, "Chunk at {} has {} bytes, expected {}. Aborting transaction.", offset, size, expected)
+      },
+      ReceiverEvent::ChunkCorrupted { offset, .. } => write!(f, "Chunk at {} is corrupted. Asked for it again.", offset),
+      ReceiverEvent::ChunkAppended { .. } => write!(f, "Appended chunk to blob."),
+      ReceiverEvent::ChunkRequested { .. } => write!(f, "Requested chunk."),
+      ReceiverEvent::UnknownBlob { blob_id } => write!(f, "END with invalid blob id: {:?}. Ignoring.", blob_id),
+      ReceiverEvent::CheckingHash { .. } => write!(f, "Checking hash."),
+      ReceiverEvent::HashMismatch { .. } => write!(f, "Checksum wrong. Sending FAIL."),
+      ReceiverEvent::BlobTruncated { bytes_received, data_size, .. } => {
+        write!(f, "Blob truncated at {} of {} bytes. Sending FAIL.", bytes_received, data_size)
+      },
+      ReceiverEvent::CommitFailed { .. } => write!(f, "Sink failed to commit blob. Sending FAIL."),
+      ReceiverEvent::Completed { .. } => write!(f, "Sent OK."),
+      ReceiverEvent::BlobAborted { reason, .. } => write!(f, "Sender aborted blob: {:?}.", reason),
+      ReceiverEvent::BlobExpired { .. } => write!(f, "Blob expired. Sent ABORT."),
+      ReceiverEvent::PeerTooOld { version } => write!(f, "Peer speaks protocol version {}. Refusing its blobs.", version),
+    }
   }
 }
 
@@ -75,12 +401,46 @@ pub trait BlobReceiverBehavior {
   fn on_ready(&mut self, data_size: usize) -> bool;
   fn on_info(&mut self, msg: &str);
   fn on_complete(&mut self, id: &[u8], array: &[u8]);
+
+  /// Structured version of `on_info()`. By default the event is formatted and passed on to it.
+  fn on_event(&mut self, event: ReceiverEvent) {
+    self.on_info(&event.to_string());
+  }
+
+  /// Returns a sink to stream the blob into as its chunks arrive. By default blobs are buffered
+  /// in memory and handed to `on_complete()` whole; blobs with a sink are never buffered, and
+  /// `on_complete()` gets an empty array for them.
+  fn new_sink(&mut self, _id: &[u8], _data_size: usize) -> Option<Box<BlobSink>> {
+    None
+  }
+
+  /// Called instead of `on_complete()` for blobs whose sender asked for a consistent
+  /// transaction. The sender waits for the bytes passed to `reply.send()` as its result; `reply`
+  /// can be moved to another thread if computing them takes a while.
+  fn on_consistent_complete(&mut self, id: &[u8], array: &[u8], reply: ConsReply) {
+    self.on_complete(id, array);
+    reply.send(vec![]);
+  }
+}
+
+/// Handle for sending the result of a consistent transaction back to its sender.
+pub struct ConsReply {
+  key: BlobKey,
+  tx: ChannelSender<(BlobKey, Vec<u8>)>
+}
+
+impl ConsReply {
+  pub fn send(self, result: Vec<u8>) {
+    if let Err(SendError((key, _))) = self.tx.send((self.key, result)) {
+      debug!("Receiver is gone. Dropping CONS for: {:?}", key);
+    }
+  }
 }
 
 pub struct BasicBlobReceiverBehavior;
 
 impl BlobReceiverBehavior for BasicBlobReceiverBehavior {
-  fn on_ready(&mut self, data_size: usize) -> bool {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
     true
   }
 
@@ -93,248 +453,929 @@ impl BlobReceiverBehavior for BasicBlobReceiverBehavior {
   }
 }
 
-pub struct BlobReceiver<'a> {
-  pub bind_address: String,
-  pub chunk_size: usize,
-  blobs: HashMap<Vec<u8>, Blob>,  // sender_id to blob
-  ctx: zmq::Context,
-  sock: zmq::Socket,
-  pub behavior: Box<BlobReceiverBehavior + 'a>
+/// Identifies a transfer: (sender_id, blob_id). One sender can have several blobs in flight.
+pub type BlobKey = (Vec<u8>, Vec<u8>);
+
+fn blob_key(sender_id: &[u8], blob_id: &[u8]) -> BlobKey {
+  (sender_id.to_vec(), blob_id.to_vec())
 }
 
-// TODO: Merge this with the Drop impl for TimedZMQTransaction.
-impl<'a> Drop for BlobReceiver<'a> {
-  fn drop(&mut self) {
-    match self.sock.close() {
-      Ok(()) => { debug!("Socket dropped") },
-      Err(e) => panic!(e)
-    }
+/// A new transfer token: 128 random bits, in hex.
+fn new_token() -> Vec<u8> {
+  let mut bytes = [0_u8; 16];
+  getrandom::fill(&mut bytes).expect("No randomness for transfer tokens");
+  bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>().into_bytes()
+}
 
-    debug!("dropping context.");
-    let mut e = self.ctx.destroy();
-    while e == Err(zmq::Error::EINTR) {
-      e = self.ctx.destroy();
-    }
-  }
+/// The algorithm to name in GOGO or RESUMED: only senders that offered some expect one.
+fn named_hash(offered: &[Vec<u8>], algorithm: &'static [u8]) -> Option<Vec<u8>> {
+  if offered.is_empty() { None } else { Some(algorithm.to_vec()) }
 }
 
-impl<'a> BlobReceiver<'a> {
-  pub fn new<B: BlobReceiverBehavior + 'a>(bind_address: &str, chunk_size: usize, b: B) -> Result<BlobReceiver<'a>, XactError> {
-    let mut ctx = zmq::Context::new();  // TODO set threads to 2
-    let mut sock = try!(ctx.socket(zmq::ROUTER));
-    try!(sock.set_linger(0));
-    try!(sock.set_maxmsgsize((chunk_size + MSG_PADDING) as i64));
-    try!(sock.set_rcvhwm(MAX_SIMUL_CHUNKS as i32));
-    try!(sock.bind(bind_address));
-    debug!("Bound interface: {}", bind_address);
-
-    Ok(BlobReceiver {
-      bind_address: bind_address.to_owned(),
+/// What a sender puts forward in START or RESUME.
+struct Offer {
+  blob_id: Vec<u8>,
+  data_size: usize,
+  consistent: bool,
+  hash_algorithms: Vec<Vec<u8>>,
+  chunk_size: Option<usize>
+}
+
+/// The receiving side of the protocol, for any number of senders and blobs, without any I/O.
+///
+/// The machine is fed messages from senders and the passage of time, and in return queues up
+/// replies for `poll_transmit()`. It talks to the application through its `behavior`.
+/// `BlobReceiver` drives one over a `Transport`, but it can just as well be driven by hand, e.g.
+/// in tests.
+pub struct ReceiverMachine<'a> {
+  /// The chunk size used when the sender doesn't propose one.
+  pub chunk_size: usize,
+  /// Bounds on the chunk size a sender may propose. Default to `chunk_size`, and to
+  /// `DEFAULT_MIN_CHUNK_SIZE` or `chunk_size` below it, whichever is smaller.
+  pub min_chunk_size: usize,
+  pub max_chunk_size: usize,
+  /// How much memory the chunks in flight on all of one sender's streams may take up. Its chunks
+  /// get smaller as it opens more streams, though never smaller than `min_chunk_size`. Defaults
+  /// to `DEFAULT_CHUNK_MEMORY`.
+  pub chunk_memory: usize,
+  /// Limits on the bytes of blobs buffered in memory, rather than streamed into a sink: in all,
+  /// for any one blob, and for any one sender. A START that would go over any of them gets NOGO.
+  /// The chunks of a blob going to a sink that wait for the ones before them count too, and no
+  /// more are asked for while they wouldn't fit.
+  /// Default to `DEFAULT_MEMORY_BUDGET`, `DEFAULT_MAX_BLOB_MEMORY` and
+  /// `DEFAULT_MAX_SENDER_MEMORY`.
+  pub memory_budget: usize,
+  pub max_blob_memory: usize,
+  pub max_sender_memory: usize,
+  /// How long a blob is kept once its sender goes quiet. Defaults to `DEFAULT_BLOB_TTL_SECONDS`.
+  pub blob_ttl: Duration,
+  /// Like `blob_ttl`, but for blobs that can still be finished after the sender restarts: ones
+  /// from senders that can RESUME, and ones going to a sink. Defaults to
+  /// `DEFAULT_RESUMABLE_TTL_SECONDS`.
+  pub resumable_ttl: Duration,
+  /// How long an END that overtook some of the chunks waits for them before the blob fails as
+  /// truncated. Defaults to `DEFAULT_END_WAIT_SECONDS`.
+  pub end_wait: Duration,
+  /// Capabilities offered to senders in PONG. Defaults to everything this crate supports.
+  pub capabilities: Vec<&'static [u8]>,
+  /// Hash algorithms we'll agree to, in case the sender offers several. Defaults to `hash::ALL`.
+  pub hash_algorithms: Vec<&'static [u8]>,
+  /// How many connections a sender may stripe one blob across, counting the one that started
+  /// it. Defaults to `DEFAULT_MAX_STREAMS`.
+  pub max_streams: usize,
+  /// Bounds on how many chunks each stream may have in flight. Within them, the window follows
+  /// the measured bandwidth-delay product; see `flow::CreditWindow`. Default to
+  /// `DEFAULT_MIN_WINDOW` and `DEFAULT_MAX_WINDOW`.
+  pub min_window: usize,
+  pub max_window: usize,
+  pub behavior: Box<BlobReceiverBehavior + 'a>,
+  blobs: HashMap<BlobKey, Blob>,
+  peers: HashMap<Vec<u8>, PeerInfo>,  // sender_id to what was agreed in PING/PONG
+  joined: HashMap<BlobKey, BlobKey>,  // (sender_id, blob_id) of a JOIN to the blob's own key
+  windows: HashMap<BlobKey, CreditWindow>,  // one per stream, by (sender_id, blob_id)
+  cons_tx: ChannelSender<(BlobKey, Vec<u8>)>,
+  cons_rx: ChannelReceiver<(BlobKey, Vec<u8>)>,
+  outbox: VecDeque<(Vec<u8>, Message)>
+}
+
+impl<'a> ReceiverMachine<'a> {
+  pub fn new<B: BlobReceiverBehavior + 'a>(chunk_size: usize, b: B) -> ReceiverMachine<'a> {
+    let (cons_tx, cons_rx) = channel();
+
+    ReceiverMachine {
       chunk_size: chunk_size,
+      min_chunk_size: cmp::min(DEFAULT_MIN_CHUNK_SIZE, chunk_size),
+      max_chunk_size: chunk_size,
+      chunk_memory: DEFAULT_CHUNK_MEMORY,
+      memory_budget: DEFAULT_MEMORY_BUDGET,
+      max_blob_memory: DEFAULT_MAX_BLOB_MEMORY,
+      max_sender_memory: DEFAULT_MAX_SENDER_MEMORY,
+      blob_ttl: Duration::from_secs(DEFAULT_BLOB_TTL_SECONDS),
+      resumable_ttl: Duration::from_secs(DEFAULT_RESUMABLE_TTL_SECONDS),
+      end_wait: Duration::from_secs(DEFAULT_END_WAIT_SECONDS),
+      capabilities: capabilities::ALL.to_vec(),
+      hash_algorithms: hash::ALL.to_vec(),
+      max_streams: DEFAULT_MAX_STREAMS,
+      min_window: DEFAULT_MIN_WINDOW,
+      max_window: DEFAULT_MAX_WINDOW,
+      behavior: Box::new(b),
       blobs: HashMap::new(),
-      ctx: ctx,
-      sock: sock,
-      behavior: Box::new(b)
-    })
+      peers: HashMap::new(),
+      joined: HashMap::new(),
+      windows: HashMap::new(),
+      cons_tx: cons_tx,
+      cons_rx: cons_rx,
+      outbox: VecDeque::new()
+    }
   }
 
-  pub fn run(&mut self, stop_rx: ChannelReceiver<bool>) {
-    loop {
-      self.prune_dead_blobs();
-      self.send_cons_msgs();
+  /// The next reply to send, and the sender to send it to.
+  pub fn poll_transmit(&mut self) -> Option<(Vec<u8>, Message)> {
+    self.outbox.pop_front()
+  }
 
-      let poll_result = self.sock.poll(zmq::POLLIN, 50);
-      if poll_result.is_err() || poll_result.unwrap() == 0 {
-        continue;
+  /// Feeds in a message from `sender_id`, as frames for `Message::decode()`. Messages that
+  /// don't decode abort the transaction they belong to, if any.
+  pub fn handle_frames(&mut self, sender_id: &[u8], frames: Vec<Vec<u8>>, now: Instant) {
+    let blob_id = protocol::blob_id_of(&frames).map(|blob_id| blob_id.to_vec());
+    match Message::decode(frames) {
+      Ok(msg) => self.handle_message(sender_id, msg, now),
+      Err(e) => {
+        debug!("RECV invalid: {}", e);
+        if let Some(blob_id) = blob_id {
+          self.abort_transaction(sender_id, &blob_id, AbortReason::INVALID_REQUEST);
+        }
       }
+    }
+  }
 
-      // TODO: Error-handling for these calls.
-      let sender_id = self.sock.recv_bytes(0).unwrap();
-      let cmd_bytes = self.sock.recv_bytes(0).unwrap();
-      match cmd_bytes.as_slice() {
-        b"PING" => {
-          debug!("RECV PING");
-          self.do_ping(&sender_id);
-        },
-        b"START" => {
-          debug!("RECV START");
-          self.do_start(&sender_id);
-        },
-        b"CHUNK" => {
-          debug!("RECV CHUNK");
-          self.do_chunk(&sender_id);
-        },
-        b"END" => {
-          debug!("RECV END");
-          self.do_end(&sender_id);
-        },
-        ref res => {
-          debug!("RECV invalid: {:?}", res);
-        }
-      };
+  /// Feeds in a message from `sender_id`.
+  pub fn handle_message(&mut self, sender_id: &[u8], msg: Message, now: Instant) {
+    match msg {
+      Message::Ping { peer } => {
+        debug!("RECV PING");
+        self.do_ping(sender_id, peer);
+      },
+      Message::Start { blob_id, data_size, consistent, hash_algorithms, chunk_size } => {
+        debug!("RECV START");
+        let offer = Offer { blob_id: blob_id, data_size: data_size, consistent: consistent,
+                            hash_algorithms: hash_algorithms, chunk_size: chunk_size };
+        self.do_start(sender_id, &offer, now);
+      },
+      Message::Resume { blob_id, data_size, consistent, hash_algorithms, chunk_size, token } => {
+        debug!("RECV RESUME");
+        let offer = Offer { blob_id: blob_id, data_size: data_size, consistent: consistent,
+                            hash_algorithms: hash_algorithms, chunk_size: chunk_size };
+        self.do_resume(sender_id, &offer, token, now);
+      },
+      Message::Chunk { blob_id, data, offset, crc } => {
+        debug!("RECV CHUNK");
+        self.do_chunk(sender_id, &blob_id, data, offset, crc, now);
+      },
+      Message::End { blob_id, hash, hash_algorithm } => {
+        debug!("RECV END");
+        self.do_end(sender_id, &blob_id, &hash, hash_algorithm, now);
+      },
+      Message::Abort { blob_id, reason } => {
+        debug!("RECV ABORT");
+        self.do_abort(sender_id, &blob_id, reason);
+      },
+      Message::Join { blob_id, token } => {
+        debug!("RECV JOIN");
+        self.do_join(sender_id, &blob_id, &token, now);
+      },
+      ref msg => {
+        debug!("RECV unexpected: {:?}", msg.command());
+      }
+    };
+  }
 
-      if stop_rx.try_recv().is_ok() {
-        self.behavior.on_info("Received shutdown signal. Exiting.");
-        break;
+  /// Lets the machine know the time, so it can expire blobs whose senders have gone quiet and ask
+  /// again for chunks that seem to have been lost. Also queues up any CONS replies that are ready.
+  /// Call this regularly, even when nothing arrives.
+  pub fn handle_timeout(&mut self, now: Instant) {
+    self.prune_dead_blobs(now);
+    self.give_up_on_chunks(now);
+    self.reclaim_credits(now);
+    self.send_cons_msgs();
+  }
+
+  /// Tells the machine that a reply from `poll_transmit()` couldn't be sent. A failed GOGO or
+  /// RESUMED aborts the transaction, since the sender will never start sending.
+  pub fn transmit_failed(&mut self, sender_id: &[u8], msg: &Message, error: XactError) {
+    match *msg {
+      Message::Gogo { ref blob_id, .. } | Message::Resumed { ref blob_id, .. } => {
+        let reply = str::from_utf8(msg.command()).unwrap_or("reply");
+        self.behavior.on_event(ReceiverEvent::ReplyFailed { blob_id: blob_id, reply: reply, error: error });
+        self.abort_transaction(sender_id, blob_id, AbortReason::INTERNAL_ERROR);
+      },
+      _ => {
+        debug!("{} message failed to send. Error: {:?}", String::from_utf8_lossy(msg.command()), error);
       }
     }
   }
 
-  fn prune_dead_blobs(&mut self) {
-    let mut blobs = &mut self.blobs;
+  fn send_to(&mut self, sender_id: &[u8], msg: Message) {
+    self.outbox.push_back((sender_id.to_vec(), msg));
+  }
 
-    let keys_to_remove = blobs.keys()
-                              .map(|k| k.to_owned())
-                              .filter(|sender_id| {
-      let blob = blobs.get(sender_id).unwrap();
-      !blob.is_alive()
-    }).collect::<Vec<Vec<u8>>>();
+  fn prune_dead_blobs(&mut self, now: Instant) {
+    let keys_to_remove = {
+      let blobs = &self.blobs;
+      blobs.keys()
+           .map(|k| k.to_owned())
+           .filter(|key| {
+        let blob = blobs.get(key).unwrap();
+        !blob.is_alive_at(now)
+      }).collect::<Vec<BlobKey>>()
+    };
 
-    for key in keys_to_remove {
-      debug!("Removing dead blob: {:?}", key);
-      blobs.remove(&key);
+    for (sender_id, blob_id) in keys_to_remove {
+      debug!("Removing dead blob: {:?}", blob_id);
+      self.abort_transaction(&sender_id, &blob_id, AbortReason::EXPIRED);
+      self.behavior.on_event(ReceiverEvent::BlobExpired { blob_id: &blob_id });
+    }
+
+    // We never hear about disconnects, so forget peers that have nothing in flight once there
+    // are too many of them.
+    if self.peers.len() > MAX_TRACKED_PEERS {
+      let live_senders = self.blobs.keys().chain(self.joined.keys()).map(|k| k.0.clone()).collect::<Vec<Vec<u8>>>();
+      self.peers.retain(|sender_id, _| live_senders.contains(sender_id));
+    }
+  }
+
+  /// Fails the blobs whose END has waited `end_wait` for chunks that never came.
+  fn give_up_on_chunks(&mut self, now: Instant) {
+    // The deadline stays on the blob, so that do_end sees it has passed.
+    let overdue = self.blobs.iter()
+                            .filter_map(|(key, blob)| match blob.pending_end {
+                              Some(ref end) if end.2 <= now => Some((key.clone(), end.clone())),
+                              _ => None,
+                            })
+                            .collect::<Vec<_>>();
+    for ((sender_id, blob_id), (hash, hash_algorithm, _)) in overdue {
+      debug!("Gave up waiting for the rest of {:?} after END.", blob_id);
+      self.do_end(&sender_id, &blob_id, &hash, hash_algorithm, now);
     }
   }
 
   fn send_cons_msgs(&mut self) {
+    while let Ok((key, result)) = self.cons_rx.try_recv() {
+      let (sender_id, blob_id) = key;
+      self.send_to(&sender_id, Message::Cons { blob_id: blob_id, result: result });
+    }
+  }
+
+  fn do_ping(&mut self, sender_id: &[u8], theirs: PeerInfo) {
+    let peer = theirs.negotiate(&self.capabilities);
+    if !peer.is_current() {
+      // Older senders frame their messages differently. A PONG with our version tells them so
+      // right away rather than leaving them to time out: senders from before versions were
+      // negotiated fail on any PONG that isn't bare.
+      self.behavior.on_event(ReceiverEvent::PeerTooOld { version: theirs.version });
+      self.send_to(sender_id, Message::Pong { peer: PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![] } });
+      self.peers.insert(sender_id.to_vec(), peer);
+      return;
+    }
+    self.send_to(sender_id, Message::Pong { peer: peer.clone() });
+    self.peers.insert(sender_id.to_vec(), peer);
+  }
+
+  /// Whether `sender_id` PINGed us with an older protocol version than ours. Senders we know
+  /// nothing about, e.g. because they were forgotten, are taken to be current.
+  fn peer_too_old(&self, sender_id: &[u8]) -> bool {
+    self.peers.get(sender_id).map_or(false, |peer| !peer.is_current())
+  }
+
+  fn peer_supports(&self, sender_id: &[u8], capability: &[u8]) -> bool {
+    self.peers.get(sender_id).map_or(false, |peer| peer.supports(capability))
+  }
 
+  /// The key of the blob that `sender_id` is sending, which may have been started on another
+  /// connection.
+  fn resolve(&self, sender_id: &[u8], blob_id: &[u8]) -> BlobKey {
+    let key = blob_key(sender_id, blob_id);
+    self.joined.get(&key).cloned().unwrap_or(key)
   }
 
-  fn do_ping(&mut self, sender_id: &[u8]) {
-    if let Err(e) = self.sock.send_multipart(&[sender_id, b"", b"PONG"], 0) {
-      debug!("Error responding to PING: {:?}", e);
+  /// Whether we ask `sender_id` for chunks by offset and take them in any order.
+  fn ranged(&self, sender_id: &[u8]) -> bool {
+    self.peer_supports(sender_id, capabilities::CRC) && self.peer_supports(sender_id, capabilities::RANGES)
+  }
+
+  /// Picks the hash algorithm for a new transaction from the ones the sender offered. Sends NOGO
+  /// and returns None if there is nothing in common.
+  fn choose_hash(&mut self, sender_id: &[u8], blob_id: &[u8], offered: &[Vec<u8>]) -> Option<&'static [u8]> {
+    let chosen = if offered.is_empty() {
+      // The sender doesn't negotiate hashes, so it's using SHA-256.
+      hash::choose(&[hash::SHA256.to_vec()], &self.hash_algorithms)
+    } else {
+      hash::choose(offered, &self.hash_algorithms)
+    };
+    if chosen.is_none() {
+      self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+      self.behavior.on_event(ReceiverEvent::NoCommonHash { blob_id: blob_id });
     }
+    chosen
   }
 
-  fn do_start(&mut self, sender_id: &[u8]) {
-    let blob_id = self.sock.recv_bytes(0).unwrap();
-    let data_size_bytes = self.sock.recv_bytes(0).unwrap();
+  /// Picks the chunk size for a blob of `data_size` bytes: what the sender `proposed`, or
+  /// `chunk_size` if nothing, kept within `[min_chunk_size, max_chunk_size]` and small enough
+  /// that a full window on every stream from `sender_id` fits in `chunk_memory`. A blob smaller
+  /// than that goes in one chunk.
+  fn choose_chunk_size(&self, sender_id: &[u8], data_size: usize, proposed: Option<usize>) -> usize {
+    let streams = 1 + self.windows.keys().filter(|&stream| self.resolve(&stream.0, &stream.1).0 == sender_id).count();
+    let affordable = self.chunk_memory / (streams * cmp::max(self.max_window, 1));
+    let chunk_size = cmp::min(cmp::min(proposed.unwrap_or(self.chunk_size), self.max_chunk_size), affordable);
+    let chunk_size = cmp::max(chunk_size, self.min_chunk_size);
+    cmp::max(cmp::min(chunk_size, data_size), 1)
+  }
 
-    let parse_result = bytes_to_int(data_size_bytes.as_slice());
-    if parse_result.is_err() {
-      debug!("Invalid START request. Aborting transaction.");
-      self.abort_transaction(&sender_id);
+  fn do_start(&mut self, sender_id: &[u8], offer: &Offer, now: Instant) {
+    let (blob_id, data_size, hash_algorithms) = (&offer.blob_id[..], offer.data_size, &offer.hash_algorithms[..]);
+    if self.peer_too_old(sender_id) {
+      self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+      return;
+    }
+    let algorithm = match self.choose_hash(sender_id, blob_id, hash_algorithms) {
+      Some(algorithm) => algorithm,
+      None => { return; }
+    };
+    if !self.open_blob(sender_id, blob_id, data_size, offer.consistent, algorithm, now) {
       return;
     }
-    let data_size = parse_result.unwrap();
 
+    let chunk_size = self.choose_chunk_size(sender_id, data_size, offer.chunk_size);
+    // Only senders that can RESUME or JOIN have a use for the token.
+    let token = if self.peer_supports(sender_id, capabilities::RESUME) || self.peer_supports(sender_id, capabilities::JOIN) {
+      Some(self.blobs[&blob_key(sender_id, blob_id)].token.clone())
+    } else {
+      None
+    };
+    self.send_to(sender_id, Message::Gogo {
+      blob_id: blob_id.to_vec(),
+      chunk_size: chunk_size,
+      hash_algorithm: named_hash(hash_algorithms, algorithm),
+      token: token
+    });
+    self.start_chunks(sender_id, blob_id, chunk_size, now);
+  }
+
+  fn do_resume(&mut self, sender_id: &[u8], offer: &Offer, token: Option<Vec<u8>>, now: Instant) {
+    let (blob_id, data_size, hash_algorithms) = (&offer.blob_id[..], offer.data_size, &offer.hash_algorithms[..]);
+    if self.peer_too_old(sender_id) {
+      self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+      return;
+    }
+    let key = blob_key(sender_id, blob_id);
+    let algorithm = match self.choose_hash(sender_id, blob_id, hash_algorithms) {
+      Some(algorithm) => algorithm,
+      None => { return; }
+    };
+
+    // The sender has probably reconnected with a new identity, so it proves the blob is its own
+    // with the token it was given. Without one, only a blob from the same identity will do. A
+    // partial blob hashed with a different algorithm can't be resumed.
+    let prev_key = self.blobs.iter()
+                             .find(|&(k, blob)| {
+                               let owned = match token {
+                                 Some(ref token) => blob.token == *token,
+                                 None => k.0 == sender_id
+                               };
+                               owned && k.1 == blob_id && blob.data_size == data_size && blob.hash.algorithm() == algorithm
+                             })
+                             .map(|(k, _)| k.to_owned());
+    match prev_key {
+      Some(prev_key) => {
+        let mut blob = self.blobs.remove(&prev_key).unwrap();
+        self.forget_streams(&prev_key);
+        blob.consistent = offer.consistent;
+        blob.touch(now);
+        self.blobs.insert(key.clone(), blob);
+        let offset = self.blobs[&key].index;
+        self.behavior.on_event(ReceiverEvent::BlobResumed { blob_id: blob_id, offset: offset });
+      },
+      None => {
+        if !self.open_blob(sender_id, blob_id, data_size, offer.consistent, algorithm, now) {
+          return;
+        }
+      }
+    }
+
+    let (offset, prefix_hash, token) = {
+      let blob = self.blobs.get(&key).unwrap();
+      (blob.index, blob.hash.hex_digest(), blob.token.clone())
+    };
+
+    let chunk_size = self.choose_chunk_size(sender_id, data_size, offer.chunk_size);
+    let resumed_msg = Message::Resumed {
+      blob_id: blob_id.to_vec(),
+      chunk_size: chunk_size,
+      offset: offset,
+      prefix_hash: prefix_hash.into_bytes(),
+      hash_algorithm: named_hash(hash_algorithms, algorithm),
+      token: Some(token)
+    };
+    self.send_to(sender_id, resumed_msg);
+    self.start_chunks(sender_id, blob_id, chunk_size, now);
+  }
+
+  /// Asks the behavior whether to accept a blob and, if so, stores a new `Blob` for the sender,
+  /// hashed with `algorithm`. Sends NOGO and returns false if the blob is refused.
+  fn open_blob(&mut self, sender_id: &[u8], blob_id: &[u8], data_size: usize, consistent: bool,
+               algorithm: &'static [u8], now: Instant) -> bool {
     if !self.behavior.on_ready(data_size) {
-      if self.sock.send_multipart(&[sender_id, b"", b"NOGO", b"0"], 0).is_err() {
-        debug!("Error sending NOGO message. Ignoring.");
+      self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+      self.behavior.on_event(ReceiverEvent::NotReady { blob_id: blob_id });
+      return false;
+    }
+
+    // A sink for the new blob may well be writing to the same place as the old one's, so the old
+    // one has to be out of the way first.
+    let key = blob_key(sender_id, blob_id);
+    if self.blobs.contains_key(&key) {
+      debug!("Replacing unfinished blob: {:?}", blob_id);
+      self.drop_blob(&key);
+    }
+
+    let mut blob = match self.behavior.new_sink(blob_id, data_size) {
+      Some(mut sink) => {
+        if !sink.on_start(blob_id, data_size) {
+          self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+          self.behavior.on_event(ReceiverEvent::SinkRefused { blob_id: blob_id });
+          return false;
+        }
+        Blob::with_sink(blob_id, data_size, sink)
+      },
+      None => {
+        if !self.fits_in_memory(sender_id, data_size) {
+          self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+          self.behavior.on_event(ReceiverEvent::OverBudget { blob_id: blob_id, data_size: data_size });
+          return false;
+        }
+        Blob::new(blob_id, data_size)
       }
-      self.behavior.on_info("Not ready. NOGO sent.");
-      return;
+    };
+    blob.consistent = consistent;
+    blob.hash = hash::new_hasher(algorithm).unwrap();
+    let resumable = blob.sink.is_some() || self.peer_supports(sender_id, capabilities::RESUME);
+    blob.set_ttl(if resumable { self.resumable_ttl } else { self.blob_ttl });
+    blob.touch(now);
+    self.blobs.insert(key, blob);
+    self.behavior.on_event(ReceiverEvent::BlobCreated { blob_id: blob_id, data_size: data_size });
+    true
+  }
+
+  /// Whether a blob of `data_size` bytes from `sender_id` can be buffered without going over
+  /// `max_blob_memory`, `max_sender_memory` or `memory_budget`. Memory is counted for the whole of
+  /// each buffered blob, not just what has arrived, so blobs that have been let in can always
+  /// finish. Blobs going to a sink only count the chunks waiting for the ones before them.
+  fn fits_in_memory(&self, sender_id: &[u8], data_size: usize) -> bool {
+    if data_size > self.max_blob_memory {
+      return false;
     }
+    let mut total = 0;
+    let mut from_sender = 0;
+    for (key, blob) in self.blobs.iter() {
+      total += blob.memory_needed();
+      if key.0.as_slice() == sender_id {
+        from_sender += blob.memory_needed();
+      }
+    }
+    from_sender + data_size <= self.max_sender_memory && total + data_size <= self.memory_budget
+  }
 
-    let blob = Blob::new(&blob_id, data_size);
-    // Do this in a new scope to allow more mutable borrows of self later.
-    {
-      let mut blobs = &mut self.blobs;
-      blobs.insert(sender_id.to_vec(), blob);
+  fn do_chunk(&mut self, sender_id: &[u8], blob_id: &[u8], chunk: Vec<u8>, offset: Option<usize>, crc: Option<u32>,
+              now: Instant) {
+    let key = self.resolve(sender_id, blob_id);
+    let elapsed = match self.blobs.get(&key) {
+      Some(blob) => now.duration_since(cmp::min(now, blob.last_activity())),
+      None => {
+        debug!("Chunk with invalid key: {:?}", &key);
+        return;
+      }
+    };
+
+    self.behavior.on_event(ReceiverEvent::ChunkReceived { blob_id: blob_id, size: chunk.len(), elapsed: elapsed });
+    // Senders that agreed to CRCs say where each chunk goes. Without RANGES, chunks are written
+    // in order, so after a corrupted chunk the ones behind it have to be sent again too. With it,
+    // chunks that arrive early wait in the blob for the ones before them, and any asked for before
+    // this one on the same stream were lost, so they're asked for again.
+    let ranged = self.ranged(sender_id);
+    let lost = match (self.windows.get_mut(&blob_key(sender_id, blob_id)), offset) {
+      (Some(window), Some(offset)) if ranged => window.on_arrival_of(offset, now),
+      (Some(window), _) => {
+        window.on_arrival(now);
+        vec![]
+      },
+      (None, _) => vec![]
+    };
+    for lost_offset in lost {
+      if !self.blobs[&key].has_chunk(lost_offset) {
+        debug!("Chunk at offset {} was overtaken. Asking for it again.", lost_offset);
+        self.request_chunk_at(sender_id, blob_id, lost_offset, now);
+      }
     }
-    self.behavior.on_info("Created new blob.");
 
-    let chunk_size_vec = int_to_bytes(self.chunk_size);
-    let chunk_size_bytes = chunk_size_vec.as_slice();
+    let (index, already_have, expected_len, resent) = {
+      let blob = self.blobs.get_mut(&key).unwrap();
+      blob.touch(now);
+      let at = offset.unwrap_or(blob.index);
+      (blob.index, offset.map_or(false, |offset| blob.has_chunk(offset)), blob.expected_chunk_len(at), blob.resent)
+    };
+    if let Some(offset) = offset {
+      if crc.map_or(false, |crc| crc != hash::crc32c(&chunk)) {
+        self.behavior.on_event(ReceiverEvent::ChunkCorrupted { blob_id: blob_id, offset: offset });
+        if ranged {
+          self.request_chunk_at(sender_id, blob_id, offset, now);
+        } else {
+          self.request_resend(sender_id, blob_id, index, now);
+        }
+        return;
+      }
+      if already_have {
+        debug!("Ignoring chunk we already have at offset {}", offset);
+        return;
+      }
+      if offset > index && !ranged {
+        // Everything sent behind a lost chunk lands here, but one RESEND for it is enough.
+        if resent != Some(index) {
+          debug!("Chunk at offset {} arrived ahead of {}. Sending RESEND.", offset, index);
+          self.request_resend(sender_id, blob_id, index, now);
+        }
+        return;
+      }
+    }
 
-    let send_result = self.sock.send_multipart(&[sender_id, b"", b"GOGO", chunk_size_bytes], 0);
-    send_result.unwrap_or_else(|e| {
-      let err_msg = format!("Error sending GOGO message: {:?}. Aborting transaction.", e);
-      self.behavior.on_info(&err_msg);
-      self.abort_transaction(&sender_id);
+    // Every chunk but the last is a full one, and the last is exactly what's left of the blob.
+    // Anything else means the sender and we have lost track of where the chunks go.
+    match expected_len {
+      Some(expected) if chunk.len() != expected => {
+        let at = offset.unwrap_or(index);
+        self.behavior.on_event(ReceiverEvent::ChunkWrongSize { blob_id: blob_id, offset: at, size: chunk.len(), expected: expected });
+        self.abort_transaction(sender_id, blob_id, AbortReason::CHUNK_REJECTED);
+        return;
+      },
+      _ => {}
+    }
+
+    // Do this in a new scope to allow more mutable borrows of self later.
+    let (written, bytes_received, data_size, pending_end) = {
+      let blob = self.blobs.get_mut(&key).unwrap();
+      let written = match offset {
+        Some(offset) if ranged => blob.receive_chunk(offset, chunk),
+        _ => blob.write_chunk(&chunk)
+      };
+      let pending_end = if blob.is_complete() { blob.pending_end.take() } else { None };
+      (written, blob.index, blob.data_size, pending_end)
+    };
+    let still_ending = self.blobs.get(&key).map_or(false, |blob| blob.pending_end.is_some());
+    if !written {
+      self.behavior.on_event(ReceiverEvent::ChunkRejected { blob_id: blob_id });
+      self.abort_transaction(sender_id, blob_id, AbortReason::CHUNK_REJECTED);
       return;
+    }
+    self.behavior.on_event(ReceiverEvent::ChunkAppended {
+      blob_id: blob_id,
+      bytes_received: bytes_received,
+      data_size: data_size
     });
 
-    self.request_chunks(&sender_id, MAX_SIMUL_CHUNKS);
+    self.request_chunks(sender_id, blob_id, now);
+    if still_ending && !ranged {
+      // The sender has sent everything, so nothing else is coming to show what's missing.
+      self.request_resend(sender_id, blob_id, bytes_received, now);
+    }
+    if let Some((hash, hash_algorithm, _)) = pending_end {
+      // END came from whoever started the blob, which may not be this connection.
+      self.do_end(&key.0, blob_id, &hash, hash_algorithm, now);
+    }
   }
 
-  fn do_chunk(&mut self, sender_id: &[u8]) {
-    if !self.blobs.contains_key(&sender_id.to_vec()) {
-      debug!("Chunk with invalid sender_id: {:?}", &sender_id);
+  fn do_end(&mut self, sender_id: &[u8], blob_id: &[u8], hash_bytes: &[u8], hash_algorithm: Option<Vec<u8>>,
+            now: Instant) {
+    let key = blob_key(sender_id, blob_id);
+    let blob_or_none = self.blobs.remove(&key);
+    if blob_or_none.is_none() {
+      self.behavior.on_event(ReceiverEvent::UnknownBlob { blob_id: blob_id });
       return;
     }
+    let mut blob = blob_or_none.unwrap();
 
-    // Do this in a new scope to allow more mutable borrows of self later.
-    {
-      let start = Instant::now();
-      let mut blob = self.blobs.get_mut(&sender_id.to_vec()).unwrap();
-      {
-        let chunk_buf = &mut blob.array[blob.index..blob.index + self.chunk_size];
-        self.sock.recv_into(chunk_buf, 0).unwrap_or_else(|e| {
-          debug!("Error receiving chunk data: {:?}", e);
-        });
+    // END can overtake chunks that are being resent. The hash is checked once they're in, or the
+    // blob fails as truncated if they don't turn up within `end_wait`. Any that are still missing
+    // may have been lost, so ask again: by offset if we asked for chunks that way, and otherwise
+    // for the next one to write, and then each one after it as it lands.
+    let deadline = blob.pending_end.as_ref().map_or(now + self.end_wait, |end| end.2);
+    if !blob.is_complete() && self.peer_supports(sender_id, capabilities::CRC) && now < deadline {
+      debug!("END arrived with {} of {} bytes. Waiting for the rest.", blob.index, blob.data_size);
+      blob.pending_end = Some((hash_bytes.to_vec(), hash_algorithm, deadline));
+      let index = blob.index;
+      let missing = blob.missing_chunks();
+      self.blobs.insert(key, blob);
+      if !self.ranged(sender_id) {
+        self.request_resend(sender_id, blob_id, index, now);
       }
+      for offset in missing {
+        self.request_chunk_at(sender_id, blob_id, offset, now);
+      }
+      return;
+    }
+    self.forget_streams(&key);
 
-      let duration = Instant::now() - start;
-      let ms = duration.as_secs() * 1000 + (duration.subsec_nanos() as f64 / 1e6) as u64;
-      let msg = format!("Received {} bytes in {} ms.", self.chunk_size, ms);
-      self.behavior.on_info(&msg);
+    if !blob.is_complete() {
+      self.behavior.on_event(ReceiverEvent::BlobTruncated {
+        blob_id: blob_id,
+        bytes_received: blob.index,
+        data_size: blob.data_size
+      });
+      blob.finish(false);
+      let reason = if blob.pending_end.is_some() { FailReason::CHUNKS_MISSING } else { FailReason::TRUNCATED };
+      self.send_to(sender_id, Message::Fail { blob_id: blob_id.to_vec(), reason: reason });
+      return;
+    }
 
-      {
-        let chunk_buf_immutable = &blob.array[blob.index..blob.index + self.chunk_size];
-        blob.hash.input(chunk_buf_immutable);
-        blob.index += self.chunk_size;
-      }
-      blob.update_ttl();
+    self.behavior.on_event(ReceiverEvent::CheckingHash { blob_id: blob_id });
+    // A sender that doesn't name the algorithm is using the one agreed at the start.
+    let same_algorithm = hash_algorithm.map_or(true, |alg| alg.as_slice() == blob.hash.algorithm());
+    if !same_algorithm || hash_bytes != blob.hash.hex_digest().as_bytes() {
+      self.behavior.on_event(ReceiverEvent::HashMismatch { blob_id: blob_id });
+      blob.finish(false);
+      self.send_to(sender_id, Message::Fail { blob_id: blob_id.to_vec(), reason: FailReason::HASH_MISMATCH });
+      return;
     }
-    self.behavior.on_info("Appended chunk to blob.");
 
-    self.request_chunks(&sender_id, 1);
+    if !blob.finish(true) {
+      self.behavior.on_event(ReceiverEvent::CommitFailed { blob_id: blob_id });
+      self.send_to(sender_id, Message::Fail { blob_id: blob_id.to_vec(), reason: FailReason::COMMIT_FAILED });
+      return;
+    }
+
+    self.send_to(sender_id, Message::Ok { blob_id: blob_id.to_vec() });
+    self.behavior.on_event(ReceiverEvent::Completed { blob_id: blob_id, data_size: blob.data_size });
+
+    if blob.consistent {
+      let reply = ConsReply { key: key, tx: self.cons_tx.clone() };
+      self.behavior.on_consistent_complete(&blob.id, &blob.array, reply);
+    } else {
+      self.behavior.on_complete(&blob.id, &blob.array);
+    }
   }
 
-  fn do_end(&mut self, sender_id: &[u8]) {
-    let hash_vec = match self.sock.recv_bytes(0) {
-      Ok(hash_vec) => {
-        hash_vec
-      },
-      Err(e) => {
-        debug!("Error receiving hash bytes: {:?}", e);
-        self.abort_transaction(&sender_id);
+  /// Adds `sender_id` as another connection for a blob that is being sent by offset, agreeing
+  /// the same capabilities as the connection that started it, and asks it for chunks. The blob
+  /// is the one with the token the sender was handed, whoever started it.
+  fn do_join(&mut self, sender_id: &[u8], blob_id: &[u8], token: &[u8], now: Instant) {
+    let owner = self.blobs.iter()
+                          .find(|&(k, blob)| k.1 == blob_id && blob.token == token && blob.chunk_size > 0)
+                          .map(|(k, _)| k.to_owned());
+    let streams = owner.as_ref().map_or(0, |owner| 1 + self.joined.values().filter(|o| *o == owner).count());
+    let owner = match owner {
+      Some(ref owner) if self.peer_supports(&owner.0, capabilities::JOIN) && streams < self.max_streams => owner.clone(),
+      _ => {
+        self.send_to(sender_id, Message::Nogo { blob_id: blob_id.to_vec() });
+        self.behavior.on_event(ReceiverEvent::JoinRefused { blob_id: blob_id });
         return;
       }
     };
-    let hash_bytes = hash_vec.as_slice();
 
-    let blob_or_none = self.blobs.remove(&sender_id.to_vec());
-    if blob_or_none.is_none() {
-      let msg = format!("END with invalid sender_id: {:?}. Ignoring.", &sender_id);
-      self.behavior.on_info(&msg);
-      return;
+    let peer = self.peers[&owner.0].clone();
+    self.peers.insert(sender_id.to_vec(), peer);
+    self.blobs.get_mut(&owner).unwrap().touch(now);
+    self.joined.insert(blob_key(sender_id, blob_id), owner);
+    self.behavior.on_event(ReceiverEvent::StreamJoined { blob_id: blob_id, streams: streams + 1 });
+    self.open_window(sender_id, blob_id);
+    self.request_chunks(sender_id, blob_id, now);
+  }
+
+  fn do_abort(&mut self, sender_id: &[u8], blob_id: &[u8], reason: AbortReason) {
+    let key = self.resolve(sender_id, blob_id);
+    self.drop_blob(&key);
+    self.behavior.on_event(ReceiverEvent::BlobAborted { blob_id: blob_id, reason: reason });
+  }
+
+  /// Sets up the blob for the chunks of `chunk_size` bytes the sender is about to send, and asks
+  /// for the first few.
+  fn start_chunks(&mut self, sender_id: &[u8], blob_id: &[u8], chunk_size: usize, now: Instant) {
+    let ranged = self.ranged(sender_id);
+    if let Some(blob) = self.blobs.get_mut(&blob_key(sender_id, blob_id)) {
+      blob.expect_chunks(chunk_size);
+      blob.track_ranges(if ranged { chunk_size } else { 0 });
     }
-    let mut blob = blob_or_none.unwrap();
+    self.open_window(sender_id, blob_id);
+    self.request_chunks(sender_id, blob_id, now);
+  }
 
-    self.behavior.on_info("Checking hash.");
-    let blob_hash_str = blob.hash.result_bytes().to_hex();
-    let blob_hash = blob_hash_str.as_bytes();
-    if hash_bytes != blob_hash {
-      self.behavior.on_info("Checksum wrong. Sending FAIL.");
-      self.sock.send_multipart(&[sender_id, b"", b"FAIL", b"Hash mismatch"], 0).unwrap_or_else(|_| ());
-      self.abort_transaction(&sender_id);
-      return;
+  /// Starts flow control afresh for the stream from `sender_id`.
+  fn open_window(&mut self, sender_id: &[u8], blob_id: &[u8]) {
+    let window = CreditWindow::new(INITIAL_WINDOW, self.min_window, self.max_window);
+    self.windows.insert(blob_key(sender_id, blob_id), window);
+  }
+
+  fn credit_issued(&mut self, sender_id: &[u8], blob_id: &[u8], now: Instant) {
+    if let Some(window) = self.windows.get_mut(&blob_key(sender_id, blob_id)) {
+      window.on_issued(now);
+    }
+  }
+
+  /// Asks for as many chunks as the stream's window has room for: by offset if the sender agreed
+  /// to RANGES, in which case no more than `next_offset()` allows, or with plain TOKENs otherwise.
+  fn request_chunks(&mut self, sender_id: &[u8], blob_id: &[u8], now: Instant) {
+    let ranged = self.ranged(sender_id);
+    let crc = self.peer_supports(sender_id, capabilities::CRC);
+    let key = self.resolve(sender_id, blob_id);
+    let num_chunks = self.windows.get(&blob_key(sender_id, blob_id)).map_or(0, |window| window.credits_due());
+    for _ in 0..num_chunks {
+      if ranged {
+        match self.next_offset(&key) {
+          Some(offset) => self.request_chunk_at(sender_id, blob_id, offset, now),
+          None => { break; }
+        }
+        continue;
+      }
+      let written = match self.blobs.get(&key) {
+        Some(blob) if crc => Some(blob.index),
+        _ => None
+      };
+      self.send_to(sender_id, Message::Token { blob_id: blob_id.to_vec(), written: written, offset: None });
+      self.credit_issued(sender_id, blob_id, now);
+      self.behavior.on_event(ReceiverEvent::ChunkRequested { blob_id: blob_id });
+    }
+  }
+
+  /// The next offset to ask for of the blob at `key`, if it's time to ask for one. Chunks are only
+  /// asked for so far past the next one to write: as many as all of the blob's streams may have in
+  /// flight, so that a lost chunk can't have the rest of the blob pile up behind it, here or at
+  /// the sender. For a blob going to a sink, the chunks that could end up waiting for the ones
+  /// before them also have to fit in `max_sender_memory` and `memory_budget`.
+  fn next_offset(&mut self, key: &BlobKey) -> Option<usize> {
+    let window = self.windows.iter()
+                             .filter(|&(stream, _)| self.resolve(&stream.0, &stream.1) == *key)
+                             .map(|(_, window)| window.window())
+                             .sum::<usize>();
+    let waiting = match self.blobs.get(key) {
+      Some(blob) if blob.sink.is_some() => blob.next_request.saturating_sub(blob.index),
+      Some(_) => 0,
+      None => { return None; }
+    };
+    if waiting > 0 && !self.has_room_for(key, waiting) {
+      return None;
+    }
+    let blob = self.blobs.get_mut(key).unwrap();
+    let limit = blob.index + window * blob.chunk_size;
+    blob.next_request(limit)
+  }
+
+  /// Whether `bytes` of the blob at `key` can wait in memory without going over
+  /// `max_sender_memory` or `memory_budget`, on top of what the other blobs need.
+  fn has_room_for(&self, key: &BlobKey, bytes: usize) -> bool {
+    let mut total = bytes;
+    let mut from_sender = bytes;
+    for (other, blob) in self.blobs.iter().filter(|&(other, _)| other != key) {
+      total += blob.memory_needed();
+      if other.0 == key.0 {
+        from_sender += blob.memory_needed();
+      }
+    }
+    from_sender <= self.max_sender_memory && total <= self.memory_budget
+  }
+
+  /// Takes back the credits that have been out too long on each stream, and hands them out again:
+  /// first for chunks that went missing, then as usual. A sender that writes in order is asked for
+  /// the next chunk to write, in case that's the one that was lost.
+  fn reclaim_credits(&mut self, now: Instant) {
+    let mut expired = vec![];
+    for (stream, window) in self.windows.iter_mut() {
+      let credits = window.expire(now);
+      if credits > 0 {
+        expired.push((stream.clone(), credits));
+      }
+    }
+
+    for ((sender_id, blob_id), credits) in expired {
+      debug!("{} credits for {:?} went unanswered. Asking again.", credits, blob_id);
+      let (index, complete, missing) = match self.blobs.get(&self.resolve(&sender_id, &blob_id)) {
+        Some(blob) => (blob.index, blob.is_complete(), blob.missing_chunks()),
+        None => { continue; }
+      };
+      if self.ranged(&sender_id) {
+        for offset in missing.into_iter().take(credits) {
+          self.request_chunk_at(&sender_id, &blob_id, offset, now);
+        }
+      } else if !complete && self.peer_supports(&sender_id, capabilities::CRC) {
+        self.request_resend(&sender_id, &blob_id, index, now);
+      }
+      self.request_chunks(&sender_id, &blob_id, now);
+    }
+  }
+
+  /// Asks a sender that writes in order to send the chunk at `offset` again.
+  fn request_resend(&mut self, sender_id: &[u8], blob_id: &[u8], offset: usize, now: Instant) {
+    if let Some(blob) = self.blobs.get_mut(&self.resolve(sender_id, blob_id)) {
+      blob.resent = Some(offset);
+    }
+    self.send_to(sender_id, Message::Resend { blob_id: blob_id.to_vec(), offset: offset });
+    self.credit_issued(sender_id, blob_id, now);
+  }
+
+  /// Asks for the chunk at `offset`, for the first time or again.
+  fn request_chunk_at(&mut self, sender_id: &[u8], blob_id: &[u8], offset: usize, now: Instant) {
+    let written = self.blobs.get(&self.resolve(sender_id, blob_id)).map_or(0, |blob| blob.index);
+    self.send_to(sender_id, Message::Token { blob_id: blob_id.to_vec(), written: Some(written), offset: Some(offset) });
+    if let Some(window) = self.windows.get_mut(&blob_key(sender_id, blob_id)) {
+      window.on_issued_for(offset, now);
     }
+    self.behavior.on_event(ReceiverEvent::ChunkRequested { blob_id: blob_id });
+  }
 
-    self.sock.send_multipart(&[sender_id, b"", b"OK", b"Great success"], 0).unwrap_or_else(|e| {
-      debug!("OK message failed to send. Error: {:?}", e);
+  /// Drops the blob and tells its sender to stop sending it, over the connection that started it.
+  /// Replies to the sender about the blob that haven't gone out yet are dropped too.
+  fn abort_transaction(&mut self, sender_id: &[u8], blob_id: &[u8], reason: AbortReason) {
+    debug!("Aborting transaction, sender_id: {:?}, blob_id: {:?}, reason: {:?}", sender_id, blob_id, reason);
+    let (owner_id, _) = self.resolve(sender_id, blob_id);
+    self.drop_blob(&blob_key(&owner_id, blob_id));
+    self.outbox.retain(|(id, msg)| {
+      !((id.as_slice() == sender_id || *id == owner_id) && msg.blob_id() == Some(blob_id))
     });
-    self.behavior.on_info("Sent OK.");
+    if !self.peer_supports(&owner_id, capabilities::ABORT) {
+      return;
+    }
+    self.send_to(&owner_id, Message::Abort { blob_id: blob_id.to_vec(), reason: reason });
+  }
 
-    self.behavior.on_info("Queueing completion action.");
-    self.behavior.on_complete(&sender_id, &blob.array);
+  /// Forgets the streams sending the blob at `key`, now that it's finished or been dropped.
+  fn forget_streams(&mut self, key: &BlobKey) {
+    {
+      let joined = &self.joined;
+      self.windows.retain(|stream, _| stream != key && joined.get(stream) != Some(key));
+    }
+    self.joined.retain(|_, owner| owner != key);
   }
 
-  fn request_chunks(&mut self, sender_id: &[u8], num_chunks: usize) {
-    for i in 0..num_chunks {
-      if let Err(e) = self.sock.send_multipart(&[sender_id, b"", b"TOKEN"], 0) {
-        debug!("Chunk {} failed to send. Error: {:?}", i, e);
-        return;
+  fn drop_blob(&mut self, key: &BlobKey) {
+    self.forget_streams(key);
+    let blobs = &mut self.blobs;
+    if let Some(mut blob) = blobs.remove(key) {
+      blob.abort();
+    }
+  }
+}
+
+/// Receives blobs over a `Transport`, driving a `ReceiverMachine`.
+pub struct BlobReceiver<'a> {
+  /// Where the receiver is listening. Empty if it was handed a transport that was already bound.
+  pub bind_address: String,
+  pub machine: ReceiverMachine<'a>,
+  transport: Box<Transport + 'a>
+}
+
+impl<'a> BlobReceiver<'a> {
+  /// Binds a ZMQ ROUTER socket at `bind_address`. A wildcard port, as in `tcp://127.0.0.1:*`,
+  /// picks a free one, and `bind_address` then says which.
+  #[cfg(feature = "zmq")]
+  pub fn new<B: BlobReceiverBehavior + 'a>(bind_address: &str, chunk_size: usize, b: B) -> Result<BlobReceiver<'a>, XactError> {
+    let transport = try!(ZmqTransport::bind(bind_address, chunk_size + MSG_PADDING, DEFAULT_MAX_WINDOW));
+    let bound_address = try!(transport.last_endpoint());
+    let mut receiver = BlobReceiver::with_transport(transport, chunk_size, b);
+    receiver.bind_address = bound_address;
+    Ok(receiver)
+  }
+
+  /// Receives over a transport that is already bound, e.g. a `StreamListener` or the bound side
+  /// of a `ChannelTransport`.
+  pub fn with_transport<T, B>(transport: T, chunk_size: usize, b: B) -> BlobReceiver<'a>
+                              where T: Transport + 'a, B: BlobReceiverBehavior + 'a {
+    BlobReceiver {
+      bind_address: String::new(),
+      machine: ReceiverMachine::new(chunk_size, b),
+      transport: Box::new(transport)
+    }
+  }
+
+  /// Serves senders until `STOP` arrives on `stop_rx`, which is checked at least every 50 ms.
+  pub fn run(&mut self, stop_rx: ChannelReceiver<bool>) {
+    // Make room for the biggest chunk a sender may talk us into.
+    if let Err(e) = self.transport.set_max_message_size(self.machine.max_chunk_size + MSG_PADDING) {
+      warn!("Couldn't raise the maximum message size: {}", e);
+    }
+    loop {
+      if stop_rx.try_recv().is_ok() {
+        self.machine.behavior.on_event(ReceiverEvent::ShuttingDown);
+        break;
       }
-      self.behavior.on_info("Requested chunk.");
+
+      self.machine.handle_timeout(Instant::now());
+      self.flush();
+
+      match self.transport.poll(Duration::from_millis(50)) {
+        Ok(true) => {},
+        _ => { continue; }
+      }
+
+      let (sender_id, frames) = match self.transport.recv() {
+        Ok(message) => message,
+        Err(e) => {
+          debug!("Error receiving message: {:?}", e);
+          continue;
+        }
+      };
+      if frames.is_empty() {
+        debug!("RECV message without a command. Ignoring.");
+        continue;
+      }
+      self.machine.handle_frames(&sender_id, frames, Instant::now());
+      self.flush();
     }
   }
 
-  fn abort_transaction(&mut self, sender_id: &[u8]) {
-    debug!("Aborting transaction, sender_id: {:?}", sender_id);
-    let mut blobs = &mut self.blobs;
-    blobs.remove(&sender_id.to_vec());
+  /// Sends everything the machine has queued up.
+  fn flush(&mut self) {
+    while let Some((sender_id, msg)) = self.machine.poll_transmit() {
+      let frames = msg.clone().encode();
+      let parts: Vec<&[u8]> = frames.iter().map(|f| f.as_slice()).collect();
+      if let Err(e) = self.transport.send(&sender_id, &parts, Duration::from_millis(REPLY_TIMEOUT_MS)) {
+        self.machine.transmit_failed(&sender_id, &msg, e);
+      }
+    }
   }
 }
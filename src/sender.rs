@@ -1,101 +1,119 @@
-use zmq;
-
 use std::str;
-use std::error::Error;
-use std::fmt;
 use std::cmp;
+use std::io::Read;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
-use serialize::hex::ToHex;
-use rustc::util::sha2::{Sha256, Digest};
+use super::{capabilities, AbortReason, ErrorKind, FailReason, PeerInfo, XactError, PROTOCOL_VERSION};
+use super::hash::{self, BlobHasher};
+use super::protocol::Message;
+use super::transport::Transport;
+#[cfg(feature = "zmq")]
+use super::transport::ZmqTransport;
 
-use super::{bytes_to_int, ErrorKind, XactError};
+const CANCEL_CHECK_MS: u64 = 50;
 
-struct TimedZMQTransaction {
-  ctx: zmq::Context,
-  sock: zmq::Socket,
-  time_to_die: Instant
+/// Lets another thread cancel whatever a `Session` is currently sending. The send fails with
+/// CANCELLED within `CANCEL_CHECK_MS`, and the receiver is sent an ABORT so it can drop the blob.
+/// If nothing is being sent, the next send is cancelled instead.
+#[derive(Clone)]
+pub struct SendHandle {
+  cancelled: Arc<AtomicBool>
 }
 
-impl Drop for TimedZMQTransaction {
-  fn drop(&mut self) {
-    match self.sock.close() {
-      Ok(()) => { debug!("socket dropped") },
-      Err(e) => panic!(e)
-    }
-
-    debug!("dropping context.");
-    let mut e = self.ctx.destroy();
-    while e == Err(zmq::Error::EINTR) {
-      e = self.ctx.destroy();
-    }
+impl Default for SendHandle {
+  fn default() -> SendHandle {
+    SendHandle::new()
   }
 }
 
-impl TimedZMQTransaction {
-  pub fn new(endpoint: &str, timeout: Duration) -> Result<TimedZMQTransaction, zmq::Error> {
-    let mut ctx = zmq::Context::new();
-    let mut sock = try!(ctx.socket(zmq::DEALER));
-    try!(sock.set_linger(0));
-    try!(sock.connect(endpoint));
+impl SendHandle {
+  pub fn new() -> SendHandle {
+    SendHandle { cancelled: Arc::new(AtomicBool::new(false)) }
+  }
 
-    let now = Instant::now();
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
 
-    Ok(TimedZMQTransaction {
-      ctx: ctx,
-      sock: sock,
-      time_to_die: now + timeout
-    })
+  fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
   }
 
-  pub fn send_multipart(&mut self, parts: &[&[u8]], timeout: Option<Duration>) -> Result<(), zmq::Error> {
-    let poll_result = try!(self.poll(timeout, zmq::POLLOUT));
-    if poll_result == 0 {
-      warn!("Poll failed in send_multipart().");
-      return Err(zmq::Error::EBUSY);
-    }
+  fn reset(&self) {
+    self.cancelled.store(false, Ordering::SeqCst);
+  }
+}
 
-    let num_parts = parts.len();
-    for (index, part) in parts.iter().enumerate() {
-      let flags = if index < num_parts - 1 { zmq::SNDMORE|zmq::DONTWAIT } else { zmq::DONTWAIT };
-      try!(self.sock.send(part, flags));
+/// A transport with a deadline: every send and receive has to finish before `time_to_die`.
+struct TimedTransaction {
+  transport: Box<Transport>,
+  time_to_die: Instant,
+  cancel: SendHandle
+}
+
+impl TimedTransaction {
+  pub fn new(transport: Box<Transport>, timeout: Duration, cancel: SendHandle) -> TimedTransaction {
+    TimedTransaction {
+      transport: transport,
+      time_to_die: Instant::now() + timeout,
+      cancel: cancel
     }
-    Ok(())
   }
 
-  pub fn recv_multipart(&mut self, timeout: Option<Duration>) -> Result<Vec<Vec<u8>>, zmq::Error> {
-    let poll_result = try!(self.poll(timeout, zmq::POLLIN));
-    if poll_result == 0 {
-      warn!("Poll failed in recv_multipart().");
-      return Err(zmq::Error::EBUSY);
-    }
+  pub fn send(&mut self, msg: Message, timeout: Option<Duration>) -> Result<(), XactError> {
+    let frames = msg.encode();
+    let parts: Vec<&[u8]> = frames.iter().map(|f| f.as_slice()).collect();
+    let remaining = self.get_remaining_duration(timeout);
+    self.transport.send(b"", &parts, remaining)
+  }
 
-    let mut parts: Vec<Vec<u8>> = vec![];
+  /// Waits for the next message, checking every `CANCEL_CHECK_MS` whether the send has been
+  /// cancelled. The frames are left for `Message::decode()`.
+  pub fn recv(&mut self, timeout: Option<Duration>) -> Result<Vec<Vec<u8>>, XactError> {
+    let deadline = Instant::now() + self.get_remaining_duration(timeout);
     loop {
-      let part = try!(self.sock.recv_bytes(zmq::DONTWAIT));
-      parts.push(part);
+      if self.cancel.is_cancelled() {
+        return Err(XactError::new(ErrorKind::CANCELLED, "Send was cancelled"));
+      }
+
+      let now = Instant::now();
+      if now >= deadline {
+        debug!("Poll failed in recv().");
+        return Err(XactError::new(ErrorKind::TIMEOUT, "Timed out waiting for a reply"));
+      }
 
-      let more_parts = try!(self.sock.get_rcvmore());
-      if !more_parts {
+      let poll_slice = cmp::min(deadline - now, Duration::from_millis(CANCEL_CHECK_MS));
+      debug!("About to poll for {:?}.", poll_slice);
+      if try!(self.transport.poll(poll_slice)) {
         break;
       }
     }
 
-    Ok(parts)
+    let (_, frames) = try!(self.transport.recv());
+    Ok(frames)
   }
 
-  pub fn poll(&mut self, timeout: Option<Duration>, events: i16) -> Result<i32, zmq::Error> {
-    let timeout_ms = self.get_remaining_ms(timeout);
-    debug!("About to poll for {} ms.", timeout_ms);
-    zmq::poll(&mut [self.sock.as_poll_item(events)], timeout_ms)
+  /// The next message, if one has already arrived.
+  pub fn try_recv(&mut self) -> Result<Option<Vec<Vec<u8>>>, XactError> {
+    if !try!(self.transport.poll(Duration::new(0, 0))) {
+      return Ok(None);
+    }
+    let (_, frames) = try!(self.transport.recv());
+    Ok(Some(frames))
   }
 
-  fn get_remaining_ms(&self, timeout: Option<Duration>) -> i64 {
-    let remaining = self.get_remaining_duration(timeout);
-    let secs = remaining.as_secs();
-    let subsec_nanos = remaining.subsec_nanos();
-    let millis = secs * 1000 + (subsec_nanos / 1e6 as u32) as u64;
-    millis as i64
+  /// Restarts the clock: everything must now be done within `timeout` from now.
+  pub fn set_timeout(&mut self, timeout: Duration) {
+    self.time_to_die = Instant::now() + timeout;
+  }
+
+  pub fn deadline(&self) -> Instant {
+    self.time_to_die
   }
 
   fn get_remaining_duration(&self, timeout: Option<Duration>) -> Duration {
@@ -111,103 +129,1167 @@ impl TimedZMQTransaction {
   }
 }
 
+#[cfg(feature = "zmq")]
 pub fn send_binary_blob<F>(endpoint: &str, blob_id: &str, data: &[u8], timeout: Duration, consistent: bool,
                    on_progress: F) -> Result<Vec<u8>, XactError> where F: Fn(&str) -> () {
+  send_reader(endpoint, blob_id, data, data.len(), timeout, consistent, on_progress)
+}
+
+/// Something that happened during a send, for callers that want more than a percentage.
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+  /// The receiver answered our PING.
+  Connected,
+  /// The receiver agreed to take the blob. `resume_token` is what to pass to `resume_reader()` to
+  /// pick the blob up again should the sender die partway through, if the receiver can resume
+  /// blobs.
+  Accepted { chunk_size: usize, resume_token: Option<Vec<u8>> },
+  /// A chunk went out. `bytes_sent` counts from the start of the blob, and `elapsed` from
+  /// `Accepted`.
+  ChunkSent { bytes_sent: usize, total: usize, elapsed: Duration },
+  /// The receiver asked for the chunk at `offset` again, e.g. because it arrived corrupted, so it
+  /// went out again.
+  ChunkResent { offset: usize },
+  /// All chunks are out and the hash has been sent; waiting for the receiver to check it.
+  Finalizing,
+  /// The receiver has the blob. `throughput` is in bytes per second.
+  Done { throughput: f64 },
+}
+
+impl ProgressEvent {
+  /// The "Progress: N%" strings that the `Fn(&str)` callbacks have always received.
+  pub fn percent_repr(&self) -> Option<String> {
+    match *self {
+      ProgressEvent::Accepted { .. } => Some(String::from("Progress: 0%")),
+      ProgressEvent::ChunkSent { total: 0, .. } => Some(String::from("Progress: 100%")),
+      ProgressEvent::ChunkSent { bytes_sent, total, .. } => {
+        Some(format!("Progress: {}%", 100 * bytes_sent as u64 / total as u64))
+      },
+      _ => None
+    }
+  }
+}
+
+/// Like `send_binary_blob()`, but starts the transaction over according to `retry` if the
+/// receiver replies FAIL. Each attempt gets its own `timeout`.
+#[cfg(feature = "zmq")]
+pub fn send_binary_blob_with_retry<F>(endpoint: &str, blob_id: &str, data: &[u8], timeout: Duration,
+                                      consistent: bool, retry: RetryPolicy, on_progress: F)
+                                      -> Result<Vec<u8>, XactError> where F: Fn(&str) -> () {
+  let mut session = try!(Session::connect(endpoint, timeout));
+  session.retry_policy = retry;
+  session.send(blob_id, data, timeout, consistent, |event: ProgressEvent| {
+    if let Some(repr) = event.percent_repr() { on_progress(&repr); }
+  })
+}
+
+/// Like `send_binary_blob()`, but can be cancelled from another thread with `handle`.
+#[cfg(feature = "zmq")]
+pub fn send_binary_blob_with_handle<F>(endpoint: &str, blob_id: &str, data: &[u8], timeout: Duration,
+                                       consistent: bool, handle: &SendHandle, on_progress: F)
+                                       -> Result<Vec<u8>, XactError> where F: Fn(&str) -> () {
+  let mut session = try!(Session::connect(endpoint, timeout));
+  session.set_handle(handle.clone());
+  session.send_reader(blob_id, data, data.len(), timeout, consistent, |event: ProgressEvent| {
+    if let Some(repr) = event.percent_repr() { on_progress(&repr); }
+  })
+}
+
+/// How many times to start a transaction over after the receiver rejects the blob with FAIL,
+/// e.g. because the data was corrupted in transit.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+  pub max_retries: usize,
+  /// How long to wait before each retry.
+  pub backoff: Duration
+}
+
+impl RetryPolicy {
+  pub fn none() -> RetryPolicy {
+    RetryPolicy { max_retries: 0, backoff: Duration::from_millis(0) }
+  }
+
+  fn should_retry(&self, e: &XactError, attempt: usize) -> bool {
+    let retryable = match *e.kind() {
+      ErrorKind::HASH_MISMATCH | ErrorKind::FAILED => true,
+      _ => false
+    };
+    retryable && attempt < self.max_retries
+  }
+}
+
+/// Like `send_binary_blob()`, but pulls the `data_length` bytes of the blob from `reader` one chunk
+/// at a time as the receiver hands out TOKENs, so the whole blob is never held in memory. A
+/// receiver that checks CRCs may ask for a chunk again until it has written it, so the chunks it
+/// has been sent or asked for past that point are kept: no more than its credit windows allow,
+/// `receiver::DEFAULT_MAX_WINDOW` chunks per stream with the receiver's defaults. Otherwise only the chunk
+/// being sent is held.
+#[cfg(feature = "zmq")]
+pub fn send_reader<R, F>(endpoint: &str, blob_id: &str, reader: R, data_length: usize, timeout: Duration,
+                         consistent: bool, on_progress: F) -> Result<Vec<u8>, XactError>
+                         where R: Read, F: Fn(&str) -> () {
+  let mut session = try!(Session::connect(endpoint, timeout));
+  session.start_transfer(blob_id, reader, data_length, consistent, |event: ProgressEvent| {
+    if let Some(repr) = event.percent_repr() { on_progress(&repr); }
+  })
+}
 
-  let mut transactor = try!(TimedZMQTransaction::new(&endpoint, timeout));
+const HEARTBEAT_INTERVAL_SECONDS: u64 = 5;
+
+/// A connection to a `BlobReceiver` that stays open across many blobs.
+///
+/// The free functions in this module set up and tear down a ZMQ context and socket for every
+/// blob. A `Session` connects once and reuses the connection, PINGing the receiver whenever it
+/// has been idle for a while to make sure it is still there. Each blob gets its own deadline.
+pub struct Session {
+  /// Applied by `send()`. Streaming sends can't be retried, since the reader has been consumed.
+  pub retry_policy: RetryPolicy,
+  /// The hash algorithms offered to the receiver, most preferred first. Receivers that don't
+  /// negotiate hashes always use SHA-256. Defaults to `hash::ALL`.
+  pub hash_algorithms: Vec<&'static [u8]>,
+  /// How many connections to stripe each blob across, counting the main one. The extra ones are
+  /// opened as they're needed, and only if the receiver can take a blob over several
+  /// connections; otherwise everything goes over the main one. Defaults to 1.
+  pub streams: usize,
+  /// The chunk size to propose to the receiver, which has the final say. If `None`, the
+  /// receiver uses its own default. Defaults to `None`.
+  pub chunk_size: Option<usize>,
+  connector: Box<Fn() -> Result<Box<Transport>, XactError>>,
+  transactor: TimedTransaction,
+  extra_streams: Vec<TimedTransaction>,
+  cancel: SendHandle,
+  peer: PeerInfo,
+  last_contact: Instant,
+  /// The tokens of transfers that failed after the receiver accepted them, by blob id.
+  resume_tokens: HashMap<String, Vec<u8>>,
+  /// Blobs a transfer of which failed over the current connection, whose replies may still be
+  /// on the way.
+  failed_blobs: HashSet<String>
+}
+
+impl Session {
+  /// Connects to the ZMQ `endpoint` and PINGs the receiver, giving up after `timeout`.
+  #[cfg(feature = "zmq")]
+  pub fn connect(endpoint: &str, timeout: Duration) -> Result<Session, XactError> {
+    let endpoint = endpoint.to_owned();
+    Session::connect_with(move || ZmqTransport::connect(&endpoint), timeout)
+  }
+
+  /// Connects over whatever transport `connect` returns and PINGs the receiver, giving up after
+  /// `timeout`. `connect` is called again by `reconnect()`.
+  pub fn connect_with<T, F>(connect: F, timeout: Duration) -> Result<Session, XactError>
+                            where T: Transport + 'static, F: Fn() -> Result<T, XactError> + 'static {
+    let connector: Box<Fn() -> Result<Box<Transport>, XactError>> = Box::new(move || {
+      connect().map(|transport| Box::new(transport) as Box<Transport>)
+    });
+    let cancel = SendHandle::new();
+    let transactor = TimedTransaction::new(try!(connector()), timeout, cancel.clone());
+    let mut session = Session {
+      retry_policy: RetryPolicy::none(),
+      hash_algorithms: hash::ALL.to_vec(),
+      streams: 1,
+      chunk_size: None,
+      connector: connector,
+      transactor: transactor,
+      extra_streams: vec![],
+      cancel: cancel,
+      peer: PeerInfo::legacy(),
+      last_contact: Instant::now(),
+      resume_tokens: HashMap::new(),
+      failed_blobs: HashSet::new()
+    };
+    try!(session.heartbeat());
+    Ok(session)
+  }
+
+  /// Throws away the connection and connects again, e.g. after a transfer failed partway through.
+  pub fn reconnect(&mut self, timeout: Duration) -> Result<(), XactError> {
+    let transport = try!((self.connector)());
+    self.transactor = TimedTransaction::new(transport, timeout, self.cancel.clone());
+    self.extra_streams.clear();
+    self.failed_blobs.clear();
+    self.heartbeat()
+  }
+
+  /// Returns a handle that can cancel this session's sends from another thread.
+  pub fn handle(&self) -> SendHandle {
+    self.cancel.clone()
+  }
+
+  /// Has `handle` cancel this session's sends from now on, in place of the one it had.
+  pub fn set_handle(&mut self, handle: SendHandle) {
+    self.transactor.cancel = handle.clone();
+    for stream in self.extra_streams.iter_mut() {
+      stream.cancel = handle.clone();
+    }
+    self.cancel = handle;
+  }
+
+  /// PINGs the receiver and waits for its PONG.
+  pub fn heartbeat(&mut self) -> Result<(), XactError> {
+    self.peer = try!(ping(&mut self.transactor));
+    self.last_contact = Instant::now();
+    Ok(())
+  }
+
+  /// The protocol version and capabilities agreed with the receiver at the last heartbeat.
+  pub fn peer(&self) -> &PeerInfo {
+    &self.peer
+  }
+
+  pub fn send<F>(&mut self, blob_id: &str, data: &[u8], timeout: Duration, consistent: bool, on_event: F)
+                 -> Result<Vec<u8>, XactError> where F: Fn(ProgressEvent) -> () {
+    let mut attempt = 0;
+    loop {
+      match self.send_reader(blob_id, data, data.len(), timeout, consistent, &on_event) {
+        Err(ref e) if self.retry_policy.should_retry(e, attempt) => {
+          attempt += 1;
+          warn!("Sending blob {} failed ({}). Retry {} of {}.", blob_id, e, attempt, self.retry_policy.max_retries);
+          thread::sleep(self.retry_policy.backoff);
+        },
+        result => { return result; }
+      }
+    }
+  }
+
+  pub fn send_reader<R, F>(&mut self, blob_id: &str, reader: R, data_length: usize, timeout: Duration,
+                           consistent: bool, on_event: F) -> Result<Vec<u8>, XactError>
+                           where R: Read, F: Fn(ProgressEvent) -> () {
+    let result = self.prepare(timeout).and_then(|()| {
+      self.start_transfer(blob_id, reader, data_length, consistent, on_event)
+    });
+    self.consume_cancel(result)
+  }
+
+  /// The token to resume `blob_id` with, if a transfer of it failed after the receiver accepted
+  /// it. `resume_reader()` uses it; it only needs to be kept if the blob will be resumed from
+  /// another `Session`, e.g. after a restart.
+  pub fn resume_token(&self, blob_id: &str) -> Option<&[u8]> {
+    self.resume_tokens.get(blob_id).map(|token| token.as_slice())
+  }
+
+  /// Sets the token `resume_reader()` uses for `blob_id`, e.g. one kept from before a restart.
+  pub fn set_resume_token(&mut self, blob_id: &str, token: &[u8]) {
+    self.resume_tokens.insert(blob_id.to_owned(), token.to_vec());
+  }
+
+  /// Picks up a transfer that was cut short, e.g. by a dropped connection or a sender restart.
+  ///
+  /// The receiver replies to RESUME with the number of bytes it already has for `blob_id` and the
+  /// hash of those bytes. `reader` must yield the blob from its beginning: the already-received
+  /// prefix is read and hashed locally, checked against the receiver's hash, and only the rest is
+  /// sent. The resume token (see `resume_token()`) is how the receiver knows the partial blob is
+  /// ours; after a restart, put the one kept from before back with `set_resume_token()`. If the
+  /// receiver has no partial blob with this id and token, the transfer starts from scratch.
+  pub fn resume_reader<R, F>(&mut self, blob_id: &str, reader: R, data_length: usize, timeout: Duration,
+                             consistent: bool, on_event: F) -> Result<Vec<u8>, XactError>
+                             where R: Read, F: Fn(ProgressEvent) -> () {
+    let result = self.prepare(timeout).and_then(|()| {
+      self.resume_transfer(blob_id, reader, data_length, consistent, on_event)
+    });
+    self.consume_cancel(result)
+  }
+
+  /// Starts the deadline for a new blob, and checks the receiver is still alive if we haven't
+  /// heard from it in a while.
+  fn prepare(&mut self, timeout: Duration) -> Result<(), XactError> {
+    self.transactor.set_timeout(timeout);
+    for stream in self.extra_streams.iter_mut() {
+      stream.set_timeout(timeout);
+    }
+    if self.last_contact.elapsed() >= Duration::from_secs(HEARTBEAT_INTERVAL_SECONDS) {
+      try!(self.heartbeat());
+    }
+    Ok(())
+  }
+
+  /// Clears the cancel flag once it has cancelled a send, so that it doesn't cancel the next one
+  /// too.
+  fn consume_cancel<T>(&self, result: Result<T, XactError>) -> Result<T, XactError> {
+    if let Err(ref e) = result {
+      if let ErrorKind::CANCELLED = *e.kind() {
+        self.cancel.reset();
+      }
+    }
+    result
+  }
+
+  /// What to put to the receiver for the next blob.
+  fn proposal(&self) -> Proposal {
+    Proposal { hash_algorithms: self.hash_algorithms.clone(), chunk_size: self.chunk_size }
+  }
+
+  /// Connects as many extra streams as the next blob will be striped across.
+  fn open_streams(&mut self) -> Result<(), XactError> {
+    let can_join = [capabilities::CRC, capabilities::RANGES, capabilities::JOIN].iter().all(|c| self.peer.supports(c));
+    let wanted = if can_join { cmp::max(self.streams, 1) - 1 } else { 0 };
+    self.extra_streams.truncate(wanted);
+    while self.extra_streams.len() < wanted {
+      let transport = try!((self.connector)());
+      let mut stream = TimedTransaction::new(transport, Duration::new(0, 0), self.cancel.clone());
+      stream.time_to_die = self.transactor.deadline();
+      self.extra_streams.push(stream);
+    }
+    Ok(())
+  }
+
+  fn start_transfer<R, F>(&mut self, blob_id: &str, reader: R, data_length: usize, consistent: bool,
+                          on_event: F) -> Result<Vec<u8>, XactError>
+                          where R: Read, F: Fn(ProgressEvent) -> () {
+    try!(self.open_streams());
+    let mut machine = SenderMachine::start(blob_id.as_bytes(), data_length, consistent, &self.peer, &self.proposal(),
+                                           self.transactor.deadline());
+    self.prepare_machine(blob_id, &mut machine);
+    let result = self.drive(&mut machine, reader, on_event);
+    self.finish_transfer(blob_id, &machine, &result);
+    result
+  }
+
+  fn resume_transfer<R, F>(&mut self, blob_id: &str, reader: R, data_length: usize, consistent: bool,
+                           on_event: F) -> Result<Vec<u8>, XactError>
+                           where R: Read, F: Fn(ProgressEvent) -> () {
+    try!(self.open_streams());
+    let token = self.resume_tokens.get(blob_id).cloned();
+    let mut machine = SenderMachine::resume(blob_id.as_bytes(), token.as_deref(), data_length, consistent,
+                                            &self.peer, &self.proposal(), self.transactor.deadline());
+    self.prepare_machine(blob_id, &mut machine);
+    let result = self.drive(&mut machine, reader, on_event);
+    self.finish_transfer(blob_id, &machine, &result);
+    result
+  }
+
+  fn prepare_machine(&self, blob_id: &str, machine: &mut SenderMachine) {
+    machine.stripe_across(self.extra_streams.len());
+    if self.failed_blobs.contains(blob_id) {
+      machine.expect_stale_replies();
+    }
+  }
+
+  /// Keeps the token of a transfer that failed after it was accepted, so it can be resumed, and
+  /// forgets it once the blob has gone through.
+  fn finish_transfer(&mut self, blob_id: &str, machine: &SenderMachine, result: &Result<Vec<u8>, XactError>) {
+    self.last_contact = Instant::now();
+    if result.is_ok() {
+      self.failed_blobs.remove(blob_id);
+    } else {
+      self.failed_blobs.insert(blob_id.to_owned());
+    }
+    match (result.is_ok(), machine.resume_token()) {
+      (true, _) => { self.resume_tokens.remove(blob_id); },
+      (false, Some(token)) => { self.resume_tokens.insert(blob_id.to_owned(), token.to_vec()); },
+      (false, None) => {}
+    }
+  }
+
+  /// Runs `machine` to completion over the transport, feeding it chunks from `reader`.
+  fn drive<R, F>(&mut self, machine: &mut SenderMachine, mut reader: R, on_event: F) -> Result<Vec<u8>, XactError>
+                 where R: Read, F: Fn(ProgressEvent) -> () {
+    loop {
+      while let Some(action) = machine.poll_action() {
+        match action {
+          SenderAction::Send(msg) => {
+            if let Err(e) = self.transactor.send(msg, None) {
+              if !machine.is_done() {
+                return Err(e);
+              }
+              // Only an ABORT goes out after the machine is done, and it's best-effort.
+              debug!("Error sending ABORT: {:?}", e);
+            }
+          },
+          SenderAction::SendOn(stream, msg) => {
+            // Whatever was lost with it gets asked for again over the main stream after END.
+            if let Err(e) = self.extra_streams[stream - 1].send(msg, None) {
+              warn!("Error sending on stream {}: {:?}", stream, e);
+            }
+          },
+          SenderAction::Read(len) => {
+            let mut chunk = vec![0; len];
+            match reader.read_exact(&mut chunk) {
+              Ok(()) => machine.on_chunk_read(chunk, Instant::now()),
+              Err(e) => machine.on_read_failed(XactError::from(e))
+            }
+          },
+          SenderAction::Event(event) => on_event(event)
+        }
+      }
+
+      if let Some(result) = machine.take_result() {
+        return result;
+      }
+
+      // With extra streams, the main one is only waited on briefly, so the others get a look in.
+      let mut heard = false;
+      for (i, stream) in self.extra_streams.iter_mut().enumerate() {
+        while let Some(frames) = try!(stream.try_recv()) {
+          machine.handle_stream_frames(i + 1, frames, Instant::now());
+          heard = true;
+        }
+      }
+      if heard {
+        continue;
+      }
+      let wait = if self.extra_streams.is_empty() { None } else { Some(Duration::from_millis(CANCEL_CHECK_MS)) };
+
+      match self.transactor.recv(wait) {
+        Ok(frames) => machine.handle_frames(frames, Instant::now()),
+        Err(e) => {
+          match *e.kind() {
+            ErrorKind::CANCELLED => machine.cancel(),
+            ErrorKind::TIMEOUT => machine.handle_timeout(Instant::now()),
+            _ => { return Err(e); }
+          }
+        }
+      }
+    }
+  }
+}
+
+/// PINGs the receiver with our version and capabilities, and returns what it agreed to in its
+/// PONG. Fails with UNSUPPORTED if the receiver speaks an older version of the protocol, e.g. if it
+/// sends a bare PONG because it predates negotiation.
+/// Whether `msg` can only be about a transfer of the blob the receiver has already accepted, so
+/// that while opening, it must be a late reply to an earlier transfer of the same blob over the
+/// same connection, e.g. one that was cancelled or timed out. TOKENs are the exception when
+/// they've merely overtaken GOGO or RESUMED; see `expect_stale_replies()`.
+fn is_leftover(msg: &Message) -> bool {
+  match *msg {
+    Message::Token { .. } | Message::Resend { .. } | Message::Ok { .. } | Message::Fail { .. } |
+    Message::Cons { .. } => true,
+    Message::Abort { reason: AbortReason::CHUNK_REJECTED, .. } |
+    Message::Abort { reason: AbortReason::EXPIRED, .. } => true,
+    _ => false
+  }
+}
+
+fn ping(transactor: &mut TimedTransaction) -> Result<PeerInfo, XactError> {
+  let ours = PeerInfo {
+    version: PROTOCOL_VERSION,
+    capabilities: capabilities::ALL.iter().map(|c| c.to_vec()).collect()
+  };
 
   debug!("Sending PING...");
   let ping_timeout = Some(Duration::from_millis(500));
-  try!(transactor.send_multipart(&[b"PING"], ping_timeout));
+  try!(transactor.send(Message::Ping { peer: ours }, ping_timeout));
   debug!("\tSent PING.");
 
   debug!("Waiting for PONG...");
-  let ping_response_parts = try!(transactor.recv_multipart(ping_timeout));
+  loop {
+    let frames = try!(transactor.recv(ping_timeout));
+    match try!(Message::decode(frames).map_err(|e| e.in_phase("ping"))) {
+      Message::Pong { peer } => {
+        debug!("\tReceived PONG.");
+        let peer = peer.negotiate(capabilities::ALL);
+        if !peer.is_current() {
+          let msg = format!("Receiver speaks protocol version {}, we need {}", peer.version, PROTOCOL_VERSION);
+          return Err(XactError::new(ErrorKind::UNSUPPORTED, &msg));
+        }
+        return Ok(peer);
+      },
+      ref msg if msg.blob_id().is_some() => {
+        debug!("Ignoring leftover message for blob: {:?}", msg.blob_id());
+      },
+      ref msg => { return Err(XactError::protocol_violation("ping", "PONG", msg)); }
+    }
+  }
+}
 
-  assert!(ping_response_parts.len() == 2, "PING response had wrong number of parts: {}", ping_response_parts.len());
-  assert!(ping_response_parts[1] == b"PONG", "Invalid PING response");
-  debug!("\tReceived PONG.");
+/// Something a `SenderMachine` needs its driver to do.
+#[derive(Debug)]
+pub enum SenderAction {
+  /// Send this message to the receiver.
+  Send(Message),
+  /// Send this message to the receiver over extra stream number `usize`, counting from 1. See
+  /// `SenderMachine::stripe_across()`.
+  SendOn(usize, Message),
+  /// Read exactly this many of the blob's next bytes and pass them to `on_chunk_read()`.
+  Read(usize),
+  /// Pass this on to whoever is waiting on the send.
+  Event(ProgressEvent),
+}
 
-  let data_length = data.len();
-  let data_size_str: String = format!("{}", data_length);
-  let data_size_msg = data_size_str.as_bytes();
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SenderState {
+  /// START or RESUME is out, and we're waiting to hear whether the receiver will take the blob.
+  Opening,
+  /// Reading and hashing the part of the blob the receiver already has.
+  Skipping,
+  /// Sending a chunk for every TOKEN.
+  Sending,
+  /// END is out, and we're waiting for OK.
+  Ending,
+  /// OK came back, and we're waiting for CONS.
+  AwaitingCons,
+  Done,
+}
 
-  debug!("Sending START...");
-  try!(transactor.send_multipart(&[b"START", blob_id.as_bytes(), data_size_msg], None));
-  debug!("\tSent START.");
+/// What a `SenderMachine` puts to the receiver in START or RESUME. The receiver has the final say
+/// on both.
+#[derive(Clone, Debug)]
+pub struct Proposal {
+  /// The hash algorithms to offer, most preferred first. Receivers that don't negotiate hashes
+  /// always use SHA-256.
+  pub hash_algorithms: Vec<&'static [u8]>,
+  /// The chunk size to propose. If `None`, the receiver uses its own default.
+  pub chunk_size: Option<usize>
+}
 
-  debug!("Waiting for GOGO ...");
-  let start_response_parts = try!(transactor.recv_multipart(None));
-  assert!(start_response_parts.len() == 3, "{}", start_response_parts.len());
+impl Default for Proposal {
+  /// Every hash algorithm in `hash::ALL`, and no chunk size.
+  fn default() -> Proposal {
+    Proposal { hash_algorithms: hash::ALL.to_vec(), chunk_size: None }
+  }
+}
 
-  let chunk_size_msg = try!(match (start_response_parts[1].as_slice(), start_response_parts[2].as_slice()) {
-    (b"NOGO", _) => Err(XactError::new(ErrorKind::NOGO, "Endpoint was not ready.")),
-    (b"GOGO", chunk_size_bytes) => Ok(chunk_size_bytes),
-    (_, _) => Err(XactError::new(ErrorKind::INVALID_RESPONSE, "Invalid chunk size"))
-  });
-  debug!("\tReceived GOGO.");
+/// A chunk kept around in case the receiver asks for it (again).
+struct RetainedChunk {
+  offset: usize,
+  data: Vec<u8>,
+  sent: bool
+}
 
-  let chunk_size = try!(bytes_to_int(chunk_size_msg));
-  debug!("Chunk size: {}", chunk_size);
+/// The sending side of one blob's transaction, without any I/O.
+///
+/// The machine is fed the receiver's messages, the chunks it asks to have read, and the passage
+/// of time, and in return hands out `SenderAction`s through `poll_action()`. `Session` drives one
+/// over a `Transport`, but it can just as well be driven by hand, e.g. in tests.
+pub struct SenderMachine {
+  blob_id: Vec<u8>,
+  data_length: usize,
+  consistent: bool,
+  resuming: bool,
+  peer: PeerInfo,
+  deadline: Instant,
+  state: SenderState,
+  /// The algorithms offered in START or RESUME. Empty if the receiver doesn't negotiate hashes.
+  hash_algorithms: Vec<&'static [u8]>,
+  hash: Box<BlobHasher>,
+  chunk_size: usize,
+  bytes_read: usize,
+  resume_point: Option<(usize, Vec<u8>)>,  // (offset, hash of the bytes before it)
+  resumed_at: usize,
+  /// The token from GOGO or RESUMED, for resuming the blob later or joining it from other
+  /// connections.
+  token: Option<Vec<u8>>,
+  bytes_sent: usize,
+  streams: usize,  // extra streams, besides the main one
+  written: usize,  // how much of the blob the receiver last said it had written
+  tokens: usize,  // TOKENs we haven't sent a chunk for yet
+  /// Offsets the receiver has asked for and we haven't sent yet, if it asks by offset, and the
+  /// streams they were asked for on.
+  requested: VecDeque<(usize, usize)>,
+  /// Chunks that have been read but may still have to be sent, oldest first. Only kept if the
+  /// receiver checks CRCs, which then asks for no more than its windows' worth past the ones it
+  /// has written.
+  unwritten: VecDeque<RetainedChunk>,
+  /// TOKENs that overtook GOGO or RESUMED, handled once it arrives.
+  early_tokens: Vec<Message>,
+  /// Whether an earlier transfer of the blob failed over the same connection, so that TOKENs
+  /// before GOGO or RESUMED are late replies to it rather than early ones to us.
+  stale_replies: bool,
+  reading: bool,
+  accepted_at: Option<Instant>,
+  actions: VecDeque<SenderAction>,
+  result: Option<Result<Vec<u8>, XactError>>
+}
 
-  on_progress("Progress: 0%");
+impl SenderMachine {
+  /// Sends `blob_id` from scratch to a receiver we agreed `peer` with, hashing it with one of the
+  /// algorithms in `proposal`, and in chunks of the size it proposes, if any. The transaction
+  /// fails with TIMEOUT if it isn't done by `deadline`.
+  pub fn start(blob_id: &[u8], data_length: usize, consistent: bool, peer: &PeerInfo, proposal: &Proposal,
+               deadline: Instant) -> SenderMachine {
+    let mut machine = SenderMachine::new(blob_id, data_length, consistent, peer, &proposal.hash_algorithms, deadline);
+    if !machine.check_support(&proposal.hash_algorithms) {
+      return machine;
+    }
 
-  let mut hash = Sha256::new();
+    machine.actions.push_back(SenderAction::Event(ProgressEvent::Connected));
+    let offered = machine.offered_hashes();
+    let proposed = machine.proposed_chunk_size(proposal.chunk_size);
+    machine.actions.push_back(SenderAction::Send(Message::Start {
+      blob_id: blob_id.to_vec(),
+      data_size: data_length,
+      consistent: consistent,
+      hash_algorithms: offered,
+      chunk_size: proposed
+    }));
+    machine
+  }
 
-  for (chunk_index, chunk) in data.chunks(chunk_size).enumerate() {
-    debug!("Waiting for TOKEN...");
-    let chunk_request_parts = try!(transactor.recv_multipart(None));
-    assert!(chunk_request_parts.len() >= 2, "{}", chunk_request_parts.len());
+  /// Like `start()`, but asks the receiver to pick up whatever it already has of `blob_id`. See
+  /// `resume_reader()`. `token` is the `resume_token()` of the transfer being picked up; without
+  /// it, the receiver only looks for a blob sent over the same connection. Falls back to `start()`
+  /// if the receiver can't resume blobs.
+  pub fn resume(blob_id: &[u8], token: Option<&[u8]>, data_length: usize, consistent: bool, peer: &PeerInfo,
+                proposal: &Proposal, deadline: Instant) -> SenderMachine {
+    if !peer.supports(capabilities::RESUME) {
+      debug!("Receiver can't resume blobs. Starting from scratch.");
+      return SenderMachine::start(blob_id, data_length, consistent, peer, proposal, deadline);
+    }
 
-    match chunk_request_parts[1].as_slice() {
-      b"TOKEN" => {
-        debug!("\tReceived TOKEN.");
-      },
-      _ => { return Err(XactError::new(ErrorKind::INVALID_RESPONSE, "Invalid chunk request")); }
-    };
+    let mut machine = SenderMachine::new(blob_id, data_length, consistent, peer, &proposal.hash_algorithms, deadline);
+    machine.resuming = true;
+    if !machine.check_support(&proposal.hash_algorithms) {
+      return machine;
+    }
 
-    debug!("Sending chunk...");
-    try!(transactor.send_multipart(&[b"CHUNK", chunk], None));
-    debug!("\tSent chunk.");
+    machine.actions.push_back(SenderAction::Event(ProgressEvent::Connected));
+    let offered = machine.offered_hashes();
+    let proposed = machine.proposed_chunk_size(proposal.chunk_size);
+    machine.actions.push_back(SenderAction::Send(Message::Resume {
+      blob_id: blob_id.to_vec(),
+      data_size: data_length,
+      consistent: consistent,
+      hash_algorithms: offered,
+      chunk_size: proposed,
+      token: token.map(|token| token.to_vec())
+    }));
+    machine
+  }
 
-    hash.input(chunk);
+  fn new(blob_id: &[u8], data_length: usize, consistent: bool, peer: &PeerInfo,
+         hash_algorithms: &[&'static [u8]], deadline: Instant) -> SenderMachine {
+    let hash_algorithms = if peer.supports(capabilities::HASHES) { hash_algorithms.to_vec() } else { vec![] };
+    SenderMachine {
+      blob_id: blob_id.to_vec(),
+      data_length: data_length,
+      consistent: consistent,
+      resuming: false,
+      peer: peer.clone(),
+      deadline: deadline,
+      state: SenderState::Opening,
+      hash_algorithms: hash_algorithms,
+      hash: Box::new(hash::Sha256Hasher::new()),
+      chunk_size: 0,
+      bytes_read: 0,
+      resume_point: None,
+      resumed_at: 0,
+      token: None,
+      bytes_sent: 0,
+      streams: 0,
+      written: 0,
+      requested: VecDeque::new(),
+      unwritten: VecDeque::new(),
+      early_tokens: vec![],
+      stale_replies: false,
+      tokens: 0,
+      reading: false,
+      accepted_at: None,
+      actions: VecDeque::new(),
+      result: None
+    }
+  }
 
-    let progress_percent_repr: String = format!("Progress: {}%", 100 * (chunk_index * chunk_size + chunk.len()) / data_length);
-    on_progress(&progress_percent_repr);
+  pub fn deadline(&self) -> Instant {
+    self.deadline
   }
 
-  let hash_hex: String = hash.result_bytes().to_hex();
-  debug!("Sending hash: {:?} ...", hash_hex);
-  try!(transactor.send_multipart(&[b"END", hash_hex.as_bytes()], None));
-  debug!("\tSent hash.");
+  pub fn is_done(&self) -> bool {
+    self.state == SenderState::Done
+  }
 
-  loop {
-    debug!("Waiting for OK...");
-    let result_parts = try!(transactor.recv_multipart(None));
-    assert!(result_parts.len() >= 2, "{}", result_parts.len());
+  /// The token the receiver handed out for this transfer, once it has accepted the blob. Pass it
+  /// to `resume()` to pick the blob up again from another connection. None if the receiver
+  /// can't resume blobs.
+  pub fn resume_token(&self) -> Option<&[u8]> {
+    self.token.as_deref()
+  }
 
-    match result_parts[1].as_slice() {
-      b"TOKEN" => {
-        debug!("Ignoring extra chunk request.");
-        continue;
+  /// The next thing for the driver to do, if any.
+  pub fn poll_action(&mut self) -> Option<SenderAction> {
+    self.actions.pop_front()
+  }
+
+  /// The outcome of the transaction, once it's over and every action has been polled.
+  pub fn take_result(&mut self) -> Option<Result<Vec<u8>, XactError>> {
+    if !self.actions.is_empty() {
+      return None;
+    }
+    self.result.take()
+  }
+
+  /// Feeds in a message from the receiver, as frames for `Message::decode()`.
+  pub fn handle_frames(&mut self, frames: Vec<Vec<u8>>, now: Instant) {
+    if self.is_done() {
+      return;
+    }
+    match Message::decode(frames) {
+      Ok(msg) => self.handle_message(msg, now),
+      Err(e) => {
+        let phase = self.phase();
+        self.fail(e.in_phase(phase));
+      }
+    }
+  }
+
+  /// Once the receiver accepts the blob, asks it to hand out TOKENs over `extra_streams` more
+  /// connections as well as the main one, by sending JOIN on each with `SenderAction::SendOn`.
+  /// What arrives on them goes to `handle_stream_message()`. Does nothing unless the receiver
+  /// agreed to JOIN and RANGES.
+  pub fn stripe_across(&mut self, extra_streams: usize) {
+    if self.ranged() && self.peer.supports(capabilities::JOIN) {
+      self.streams = extra_streams;
+    }
+  }
+
+  /// Tells the machine that an earlier transfer of the same blob failed over this connection, e.g.
+  /// because it was cancelled or timed out, so its TOKENs may still be on the way. Those that come
+  /// before GOGO or RESUMED are then ignored, rather than taken to have overtaken it.
+  pub fn expect_stale_replies(&mut self) {
+    self.stale_replies = true;
+  }
+
+  /// Like `handle_frames()`, for a message that arrived on extra stream number `stream`.
+  pub fn handle_stream_frames(&mut self, stream: usize, frames: Vec<Vec<u8>>, now: Instant) {
+    if stream == 0 {
+      return self.handle_frames(frames, now);
+    }
+    match Message::decode(frames) {
+      Ok(msg) => self.handle_stream_message(stream, msg, now),
+      Err(e) => debug!("Ignoring invalid message on stream {}: {}", stream, e)
+    }
+  }
+
+  /// Like `handle_message()`, for a message that arrived on extra stream number `stream`. Only
+  /// TOKENs are expected there; everything else about the blob goes over the main stream.
+  pub fn handle_stream_message(&mut self, stream: usize, msg: Message, now: Instant) {
+    if stream == 0 {
+      return self.handle_message(msg, now);
+    }
+    if self.is_done() || msg.blob_id() != Some(self.blob_id.as_slice()) {
+      return;
+    }
+
+    match (self.state, msg) {
+      (SenderState::Skipping, Message::Token { written, offset: Some(offset), .. }) |
+      (SenderState::Sending, Message::Token { written, offset: Some(offset), .. }) => {
+        self.forget_written(written);
+        self.requested.push_back((offset, stream));
+        self.pump(now);
       },
-      b"OK" => {
+      (SenderState::Ending, Message::Token { written, offset: Some(offset), .. }) => {
+        self.forget_written(written);
+        self.send_retained(offset, stream, now);
+      },
+      (_, Message::Nogo { .. }) => {
+        debug!("Receiver wouldn't let stream {} join.", stream);
+      },
+      (_, ref msg) => {
+        debug!("Ignoring {:?} on stream {}.", String::from_utf8_lossy(msg.command()), stream);
+      }
+    }
+  }
+
+  /// Feeds in a message from the receiver. Messages about other blobs are ignored.
+  pub fn handle_message(&mut self, msg: Message, now: Instant) {
+    if self.is_done() {
+      return;
+    }
+    if msg.blob_id() != Some(self.blob_id.as_slice()) {
+      debug!("Ignoring message not about this blob: {:?}", msg.command());
+      return;
+    }
+
+    let phase = self.phase();
+    match (self.state, self.resuming, msg) {
+      (SenderState::Opening, _, msg @ Message::Token { .. }) if !self.stale_replies => {
+        debug!("\tReceived TOKEN before the blob was accepted.");
+        self.early_tokens.push(msg);
+      },
+      (SenderState::Opening, _, ref msg) if is_leftover(msg) => {
+        debug!("Ignoring {} left over from an earlier transfer.", String::from_utf8_lossy(msg.command()));
+      },
+      (_, _, Message::Abort { reason, .. }) => {
+        debug!("Receiver aborted blob: {:?}", reason);
+        self.fail(XactError::new(ErrorKind::ABORTED(reason), "Receiver aborted the transaction"));
+      },
+      (SenderState::Opening, _, Message::Nogo { .. }) => {
+        self.fail(XactError::new(ErrorKind::NOGO, "Endpoint was not ready."));
+      },
+      (SenderState::Opening, false, Message::Gogo { chunk_size, hash_algorithm, token, .. }) => {
+        debug!("\tReceived GOGO.");
+        self.token = token;
+        if self.accept(chunk_size, hash_algorithm, now) {
+          self.state = SenderState::Sending;
+          self.handle_early_tokens(now);
+          self.pump(now);
+        }
+      },
+      (SenderState::Opening, true, Message::Resumed { chunk_size, offset, prefix_hash, hash_algorithm, token, .. }) => {
+        debug!("\tReceived RESUMED.");
+        self.token = token;
+        if offset > self.data_length {
+          self.fail(XactError::new(ErrorKind::INVALID_RESPONSE, "Resume offset is past the end of the blob"));
+          return;
+        }
+        debug!("Resuming at offset {}.", offset);
+        if self.accept(chunk_size, hash_algorithm, now) {
+          self.resume_point = Some((offset, prefix_hash));
+          self.state = SenderState::Skipping;
+          self.handle_early_tokens(now);
+          self.pump(now);
+        }
+      },
+      (SenderState::Opening, resuming, ref msg) => {
+        let expected = if resuming { "RESUMED or NOGO" } else { "GOGO or NOGO" };
+        self.fail(XactError::protocol_violation(phase, expected, msg));
+      },
+      (_, false, Message::Gogo { .. }) | (_, true, Message::Resumed { .. }) => {
+        debug!("Ignoring repeated reply to the start of the blob.");
+      },
+      (SenderState::Skipping, _, Message::Token { written, offset, .. }) |
+      (SenderState::Sending, _, Message::Token { written, offset, .. }) => {
+        debug!("\tReceived TOKEN.");
+        self.forget_written(written);
+        match offset {
+          Some(offset) if self.ranged() => self.requested.push_back((offset, 0)),
+          _ => self.tokens += 1
+        }
+        self.pump(now);
+      },
+      (SenderState::Sending, _, Message::Resend { offset, .. }) |
+      (SenderState::Ending, _, Message::Resend { offset, .. }) => {
+        debug!("\tReceived RESEND.");
+        self.send_retained(offset, 0, now);
+      },
+      (SenderState::Skipping, _, ref msg) | (SenderState::Sending, _, ref msg) => {
+        self.fail(XactError::protocol_violation(phase, "TOKEN", msg));
+      },
+      (SenderState::Ending, _, Message::Token { written, offset, .. }) => {
+        self.forget_written(written);
+        match offset {
+          Some(offset) if self.ranged() => {
+            debug!("Receiver is still missing the chunk at offset {}.", offset);
+            self.send_retained(offset, 0, now);
+          },
+          _ => debug!("Ignoring extra chunk request.")
+        }
+      },
+      (SenderState::Ending, _, Message::Ok { .. }) => {
         debug!("\tReceived OK.");
-        break;
+        if self.consistent {
+          self.state = SenderState::AwaitingCons;
+        } else {
+          self.finish(vec![], now);
+        }
+      },
+      (SenderState::Ending, _, Message::Fail { reason, .. }) => {
+        debug!("\tReceived FAIL: {:?}", reason);
+        match reason {
+          FailReason::HASH_MISMATCH => self.fail(XactError::new(ErrorKind::HASH_MISMATCH, "Hash mismatch")),
+          reason => self.fail(XactError::new(ErrorKind::FAILED, &format!("Receiver failed the blob: {:?}", reason)))
+        }
+      },
+      (SenderState::Ending, _, ref msg) => {
+        self.fail(XactError::protocol_violation(phase, "OK", msg));
+      },
+      (SenderState::AwaitingCons, _, Message::Cons { result, .. }) => {
+        self.finish(result, now);
+      },
+      (SenderState::AwaitingCons, _, ref msg) => {
+        self.fail(XactError::protocol_violation(phase, "CONS", msg));
+      },
+      (SenderState::Done, _, _) => {}
+    }
+  }
+
+  /// Hands over the bytes asked for by the last `SenderAction::Read`.
+  pub fn on_chunk_read(&mut self, chunk: Vec<u8>, now: Instant) {
+    if self.is_done() || !self.reading {
+      return;
+    }
+    self.reading = false;
+    self.hash.input(&chunk);
+    let offset = self.bytes_read;
+    self.bytes_read += chunk.len();
+
+    if self.state == SenderState::Sending {
+      if self.ranged() {
+        // Sent once the receiver asks for it, which it may already have done.
+        self.unwritten.push_back(RetainedChunk { offset: offset, data: chunk, sent: false });
+      } else {
+        debug!("Sending chunk...");
+        let msg = if self.peer.supports(capabilities::CRC) {
+          self.unwritten.push_back(RetainedChunk { offset: offset, data: chunk.clone(), sent: true });
+          self.chunk_message(offset, chunk)
+        } else {
+          Message::Chunk { blob_id: self.blob_id.clone(), data: chunk, offset: None, crc: None }
+        };
+        self.actions.push_back(SenderAction::Send(msg));
+        self.bytes_sent = self.bytes_read;
+        self.chunk_sent(now);
+      }
+    }
+    self.pump(now);
+  }
+
+  /// Lets the machine know the time. Fails the transaction with TIMEOUT once the deadline has
+  /// passed.
+  pub fn handle_timeout(&mut self, now: Instant) {
+    if !self.is_done() && now >= self.deadline {
+      warn!("Timed out sending blob: {:?}", self.blob_id);
+      self.fail(XactError::new(ErrorKind::TIMEOUT, "Timed out waiting for a reply"));
+    }
+  }
+
+  /// Gives up on the blob, and tells the receiver to drop it if it knows how.
+  pub fn cancel(&mut self) {
+    self.abort(AbortReason::CANCELLED, XactError::new(ErrorKind::CANCELLED, "Send was cancelled"));
+  }
+
+  /// Gives up on the blob because the bytes asked for by the last `SenderAction::Read` couldn't
+  /// be read, and tells the receiver to drop it if it knows how. Fails with `error`.
+  pub fn on_read_failed(&mut self, error: XactError) {
+    self.abort(AbortReason::INTERNAL_ERROR, error);
+  }
+
+  fn abort(&mut self, reason: AbortReason, error: XactError) {
+    if self.is_done() {
+      return;
+    }
+    if self.peer.supports(capabilities::ABORT) {
+      debug!("Sending ABORT...");
+      self.actions.push_back(SenderAction::Send(Message::Abort { blob_id: self.blob_id.clone(), reason: reason }));
+    }
+    self.fail(error);
+  }
+
+  /// The name of the current part of the transaction, for PROTOCOL_VIOLATION errors.
+  fn phase(&self) -> &'static str {
+    match self.state {
+      SenderState::Opening if self.resuming => "resume",
+      SenderState::Opening => "start",
+      SenderState::Skipping | SenderState::Sending => "transfer",
+      SenderState::Ending => "end",
+      SenderState::AwaitingCons => "consistency",
+      SenderState::Done => "done"
+    }
+  }
+
+  /// Fails the transaction if the receiver can't do what was asked of it.
+  fn check_support(&mut self, hash_algorithms: &[&'static [u8]]) -> bool {
+    if self.consistent && !self.peer.supports(capabilities::CONS) {
+      self.fail(XactError::new(ErrorKind::UNSUPPORTED, "Receiver doesn't support consistent transactions"));
+      return false;
+    }
+    if hash_algorithms.is_empty() {
+      self.fail(XactError::new(ErrorKind::UNSUPPORTED, "No hash algorithms to offer"));
+      return false;
+    }
+    if !self.peer.supports(capabilities::HASHES) && !hash_algorithms.contains(&hash::SHA256) {
+      self.fail(XactError::new(ErrorKind::UNSUPPORTED, "Receiver only hashes with SHA-256"));
+      return false;
+    }
+    true
+  }
+
+  fn offered_hashes(&self) -> Vec<Vec<u8>> {
+    self.hash_algorithms.iter().map(|alg| alg.to_vec()).collect()
+  }
+
+  /// The chunk size to put in START or RESUME. Receivers that can't read one don't get one.
+  fn proposed_chunk_size(&self, chunk_size: Option<usize>) -> Option<usize> {
+    if chunk_size.is_some() && !self.peer.supports(capabilities::CHUNK_SIZE) {
+      debug!("Receiver can't take a chunk size. It will choose one itself.");
+      return None;
+    }
+    chunk_size
+  }
+
+  fn accept(&mut self, chunk_size: usize, hash_algorithm: Option<Vec<u8>>, now: Instant) -> bool {
+    debug!("Chunk size: {}", chunk_size);
+    if chunk_size == 0 {
+      self.fail(XactError::new(ErrorKind::INVALID_RESPONSE, "Chunk size must be positive"));
+      return false;
+    }
+
+    // A receiver that doesn't name an algorithm is using SHA-256.
+    let algorithm = hash_algorithm.unwrap_or(hash::SHA256.to_vec());
+    let offered = self.hash_algorithms.is_empty() || self.hash_algorithms.contains(&algorithm.as_slice());
+    match hash::new_hasher(&algorithm) {
+      Some(hasher) if offered => {
+        debug!("Hash algorithm: {}", String::from_utf8_lossy(&algorithm));
+        self.hash = hasher;
       },
-      _ => { return Err(XactError::new(ErrorKind::INVALID_RESPONSE, "Invalid end response")); }
+      _ => {
+        self.fail(XactError::new(ErrorKind::INVALID_RESPONSE, "Receiver chose a hash algorithm we didn't offer"));
+        return false;
+      }
+    }
+    self.chunk_size = chunk_size;
+    self.accepted_at = Some(now);
+    let resume_token = self.token.clone();
+    self.actions.push_back(SenderAction::Event(ProgressEvent::Accepted { chunk_size: chunk_size, resume_token: resume_token }));
+    // The receiver only lets in connections that bring the blob's token.
+    if let Some(ref token) = self.token {
+      for stream in 1..self.streams + 1 {
+        self.actions.push_back(SenderAction::SendOn(stream, Message::Join { blob_id: self.blob_id.clone(), token: token.clone() }));
+      }
+    }
+    true
+  }
+
+  /// Hands the machine the TOKENs that arrived before the blob was accepted.
+  fn handle_early_tokens(&mut self, now: Instant) {
+    for msg in mem::take(&mut self.early_tokens) {
+      self.handle_message(msg, now);
     }
   }
 
-  if consistent {
-    let result_parts = try!(transactor.recv_multipart(None));
-    assert!(result_parts.len() == 3, "{}", result_parts.len());
-    assert!(result_parts[1] == b"CONS", "Invalid consistency response");
+  /// Asks for the next read, or sends END once everything has been sent.
+  fn pump(&mut self, now: Instant) {
+    if self.reading {
+      return;
+    }
+
+    if self.state == SenderState::Skipping {
+      let offset = self.resume_point.as_ref().map_or(0, |&(offset, _)| offset);
+      if self.bytes_read < offset {
+        self.read(offset);
+        return;
+      }
+
+      let prefix_hash_hex = self.hash.hex_digest();
+      let prefix_matches = self.resume_point.as_ref().map_or(false, |(_, hash)| hash.as_slice() == prefix_hash_hex.as_bytes());
+      if !prefix_matches {
+        self.fail(XactError::new(ErrorKind::INVALID_RESPONSE, "Receiver's partial blob doesn't match our data"));
+        return;
+      }
+      self.resumed_at = offset;
+      self.bytes_sent = offset;
+      self.state = SenderState::Sending;
+    }
+
+    if self.state == SenderState::Sending && self.ranged() {
+      while let Some((offset, stream)) = self.requested.front().cloned() {
+        let on_boundary = offset >= self.resumed_at && (offset - self.resumed_at) % self.chunk_size == 0;
+        if offset >= self.data_length || !on_boundary {
+          self.fail(XactError::new(ErrorKind::INVALID_RESPONSE, "Receiver asked for a chunk that isn't in the blob"));
+          return;
+        }
+        if offset >= self.bytes_read {
+          // Read up to it. Anything skipped on the way is kept until it's asked for.
+          let data_length = self.data_length;
+          self.read(data_length);
+          return;
+        }
+        self.requested.pop_front();
+        self.send_retained(offset, stream, now);
+        if self.is_done() {
+          return;
+        }
+      }
+      // Chunks whose TOKEN went missing are left for the receiver to ask for again after END.
+      if self.bytes_read == self.data_length {
+        self.end();
+      }
+    } else if self.state == SenderState::Sending {
+      if self.bytes_read == self.data_length {
+        self.end();
+      } else if self.tokens > 0 {
+        self.tokens -= 1;
+        let data_length = self.data_length;
+        self.read(data_length);
+      }
+    }
+  }
+
+  /// Whether the receiver asks for chunks by offset and takes them in any order.
+  fn ranged(&self) -> bool {
+    self.peer.supports(capabilities::CRC) && self.peer.supports(capabilities::RANGES)
+  }
+
+  fn chunk_sent(&mut self, now: Instant) {
+    let elapsed = now.duration_since(self.accepted_at.unwrap_or(now));
+    self.actions.push_back(SenderAction::Event(ProgressEvent::ChunkSent {
+      bytes_sent: self.bytes_sent,
+      total: self.data_length,
+      elapsed: elapsed
+    }));
+  }
+
+  fn chunk_message(&self, offset: usize, data: Vec<u8>) -> Message {
+    let crc = hash::crc32c(&data);
+    Message::Chunk { blob_id: self.blob_id.clone(), data: data, offset: Some(offset), crc: Some(crc) }
+  }
+
+  /// Drops the chunks that the receiver has written, now that they won't need resending.
+  fn forget_written(&mut self, written: Option<usize>) {
+    if let Some(written) = written {
+      self.written = cmp::max(self.written, written);
+      while self.unwritten.front().map_or(false, |chunk| chunk.offset + chunk.data.len() <= written) {
+        self.unwritten.pop_front();
+      }
+    }
+  }
+
+  /// Sends the kept chunk at `offset` on `stream`, for the first time or again.
+  fn send_retained(&mut self, offset: usize, stream: usize, now: Instant) {
+    if offset < self.written {
+      debug!("Receiver already has the chunk at offset {}.", offset);
+      return;
+    }
+    let found = self.unwritten.iter_mut().find(|chunk| chunk.offset == offset).map(|chunk| {
+      let first_time = !chunk.sent;
+      chunk.sent = true;
+      (chunk.data.clone(), first_time)
+    });
+    let (data, first_time) = match found {
+      Some(found) => found,
+      None => {
+        self.fail(XactError::new(ErrorKind::INVALID_RESPONSE, "Receiver asked for a chunk we no longer have"));
+        return;
+      }
+    };
+
+    let length = data.len();
+    let msg = self.chunk_message(offset, data);
+    if stream == 0 {
+      self.actions.push_back(SenderAction::Send(msg));
+    } else {
+      self.actions.push_back(SenderAction::SendOn(stream, msg));
+    }
+    if first_time {
+      debug!("Sending chunk at offset {}...", offset);
+      self.bytes_sent += length;
+      self.chunk_sent(now);
+    } else {
+      debug!("Resending chunk at offset {}...", offset);
+      self.actions.push_back(SenderAction::Event(ProgressEvent::ChunkResent { offset: offset }));
+    }
+  }
+
+  /// Asks for the next chunk's worth of bytes, stopping at `limit`.
+  fn read(&mut self, limit: usize) {
+    let chunk_length = cmp::min(self.chunk_size, limit - self.bytes_read);
+    self.reading = true;
+    self.actions.push_back(SenderAction::Read(chunk_length));
+  }
+
+  fn end(&mut self) {
+    let hash_hex = self.hash.hex_digest();
+    debug!("Sending hash: {:?} ...", hash_hex);
+    let hash_algorithm = if self.hash_algorithms.is_empty() { None } else { Some(self.hash.algorithm().to_vec()) };
+    self.actions.push_back(SenderAction::Send(Message::End {
+      blob_id: self.blob_id.clone(),
+      hash: hash_hex.into_bytes(),
+      hash_algorithm: hash_algorithm
+    }));
+    self.actions.push_back(SenderAction::Event(ProgressEvent::Finalizing));
+    self.state = SenderState::Ending;
+  }
+
+  fn finish(&mut self, result: Vec<u8>, now: Instant) {
+    let elapsed = now.duration_since(self.accepted_at.unwrap_or(now));
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    let throughput = if secs > 0.0 { (self.bytes_read - self.resumed_at) as f64 / secs } else { 0.0 };
+    self.actions.push_back(SenderAction::Event(ProgressEvent::Done { throughput: throughput }));
+    self.state = SenderState::Done;
+    self.result = Some(Ok(result));
+  }
 
-    let res = result_parts[2].clone();
-    Ok(res)
-  } else {
-    debug!("Exiting send_binary_blob().");
-    Ok(vec![])
+  fn fail(&mut self, e: XactError) {
+    self.state = SenderState::Done;
+    self.result = Some(Err(e));
   }
 }
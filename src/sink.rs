@@ -0,0 +1,196 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::receiver::BlobReceiverBehavior;
+
+/// Destination for the bytes of an incoming blob.
+///
+/// A `BlobReceiverBehavior` can hand one of these out for each blob it accepts, in which case the
+/// receiver streams every CHUNK straight into the sink as it arrives instead of buffering the
+/// whole blob in `Blob::array`.
+pub trait BlobSink {
+  /// Called once before any chunks arrive. Returning false rejects the blob with a NOGO.
+  fn on_start(&mut self, id: &[u8], data_size: usize) -> bool;
+
+  /// Called for each chunk, in order. `offset` is the position of `bytes` within the blob.
+  /// Returning false aborts the transaction.
+  fn on_chunk(&mut self, offset: usize, bytes: &[u8]) -> bool;
+
+  /// Called once all chunks have arrived and the hash has been checked. If `hash_ok` is true,
+  /// the return value says whether the blob was successfully committed; a false return makes
+  /// the receiver reply FAIL instead of OK.
+  fn on_finish(&mut self, hash_ok: bool) -> bool;
+
+  /// Called if the blob is thrown away before `on_finish()`: the sender aborted, the transaction
+  /// failed, or the blob's TTL expired.
+  fn on_abort(&mut self) {}
+}
+
+/// Numbers partial files, so that two senders with the same blob id never share one.
+static NEXT_PARTIAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes each blob to a partial file of its own, `<dir>/<blob_id>.<n>.partial`, then fsyncs it
+/// and renames it to `<dir>/<blob_id>` once the hash has been verified. The partial file is
+/// deleted if the blob fails, is aborted, or can't be committed.
+pub struct FileSink {
+  dir: PathBuf,
+  partial_path: PathBuf,
+  final_path: PathBuf,
+  file: Option<File>,
+  partial_exists: bool  // until the partial file is renamed or removed
+}
+
+impl FileSink {
+  pub fn new<P: AsRef<Path>>(dir: P) -> FileSink {
+    FileSink {
+      dir: dir.as_ref().to_path_buf(),
+      partial_path: PathBuf::new(),
+      final_path: PathBuf::new(),
+      file: None,
+      partial_exists: false
+    }
+  }
+
+  pub fn final_path(&self) -> &Path {
+    &self.final_path
+  }
+
+  fn commit(&mut self) -> io::Result<()> {
+    if let Some(file) = self.file.take() {
+      try!(file.sync_all());
+    }
+    try!(fs::rename(&self.partial_path, &self.final_path));
+    self.partial_exists = false;
+
+    // Make the rename itself durable.
+    let dir = try!(File::open(&self.dir));
+    dir.sync_all()
+  }
+
+  fn discard(&mut self) {
+    self.file = None;
+    if self.partial_exists {
+      self.partial_exists = false;
+      if let Err(e) = fs::remove_file(&self.partial_path) {
+        warn!("Unable to remove {:?}: {}", self.partial_path, e);
+      }
+    }
+  }
+
+  /// Creates a partial file for `name` that no other sink is using, even one left behind by
+  /// an earlier run.
+  fn create_partial(&mut self, name: &str) -> io::Result<File> {
+    loop {
+      let n = NEXT_PARTIAL.fetch_add(1, Ordering::SeqCst);
+      self.partial_path = self.dir.join(format!("{}.{}.partial", name, n));
+      match OpenOptions::new().write(true).create_new(true).open(&self.partial_path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+        result => { return result; }
+      }
+    }
+  }
+}
+
+impl Drop for FileSink {
+  fn drop(&mut self) {
+    self.discard();
+  }
+}
+
+impl BlobSink for FileSink {
+  fn on_start(&mut self, id: &[u8], data_size: usize) -> bool {
+    let name = match str::from_utf8(id) {
+      Ok(name) if is_safe_file_name(name) => name,
+      _ => {
+        warn!("Blob id {:?} is not usable as a file name.", id);
+        return false;
+      }
+    };
+
+    self.final_path = self.dir.join(name);
+
+    let file = match self.create_partial(name) {
+      Ok(file) => file,
+      Err(e) => {
+        warn!("Unable to create {:?}: {}", self.partial_path, e);
+        return false;
+      }
+    };
+    self.file = Some(file);
+    self.partial_exists = true;
+
+    if let Err(e) = self.file.as_ref().unwrap().set_len(data_size as u64) {
+      warn!("Unable to size {:?}: {}", self.partial_path, e);
+      self.discard();
+      return false;
+    }
+    true
+  }
+
+  fn on_chunk(&mut self, offset: usize, bytes: &[u8]) -> bool {
+    let result = match self.file {
+      Some(ref mut file) => file.seek(SeekFrom::Start(offset as u64)).and_then(|_| file.write_all(bytes)),
+      None => return false
+    };
+
+    if let Err(e) = result {
+      warn!("Unable to write to {:?}: {}", self.partial_path, e);
+      return false;
+    }
+    true
+  }
+
+  fn on_finish(&mut self, hash_ok: bool) -> bool {
+    if !hash_ok {
+      self.discard();
+      return false;
+    }
+
+    if let Err(e) = self.commit() {
+      warn!("Unable to commit {:?}: {}", self.final_path, e);
+      self.discard();
+      return false;
+    }
+    true
+  }
+
+  fn on_abort(&mut self) {
+    self.discard();
+  }
+}
+
+fn is_safe_file_name(name: &str) -> bool {
+  !name.is_empty() && !name.starts_with('.') && !name.contains('/') && !name.contains('\\')
+}
+
+/// Receiver behavior that accepts every blob and writes it to a directory through a `FileSink`.
+pub struct FileSinkBehavior {
+  pub dir: PathBuf
+}
+
+impl FileSinkBehavior {
+  pub fn new<P: AsRef<Path>>(dir: P) -> FileSinkBehavior {
+    FileSinkBehavior { dir: dir.as_ref().to_path_buf() }
+  }
+}
+
+impl BlobReceiverBehavior for FileSinkBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, id: &[u8], _array: &[u8]) {
+    info!("Blob id: {:?} written to {:?}.", id, self.dir);
+  }
+
+  fn new_sink(&mut self, _id: &[u8], _data_size: usize) -> Option<Box<BlobSink>> {
+    Some(Box::new(FileSink::new(&self.dir)))
+  }
+}
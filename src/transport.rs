@@ -0,0 +1,826 @@
+//! Ways of moving multipart messages between a sender and a receiver.
+//!
+//! The protocol only needs to send and receive whole multipart messages, and to know which peer
+//! a message came from. A `Transport` provides exactly that, so the same `Session` and
+//! `BlobReceiver` can run over ZMQ, a plain TCP or Unix socket, or an in-process channel.
+//!
+//! Peers are identified by opaque byte strings. On the connecting side there is only one peer,
+//! and its identity is empty.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+#[cfg(feature = "zmq")]
+use zmq;
+
+use super::{int_to_bytes, ErrorKind, XactError};
+
+/// How often the framed transports check their sockets while polling.
+const STREAM_POLL_MS: u64 = 1;
+
+pub trait Transport {
+  /// Sends one multipart message to `peer`, giving up after `timeout` if it can't be handed off.
+  fn send(&mut self, peer: &[u8], frames: &[&[u8]], timeout: Duration) -> Result<(), XactError>;
+
+  /// Waits up to `timeout` for a message to arrive. Returns whether `recv()` will return without
+  /// blocking.
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError>;
+
+  /// Takes the next message, waiting for one if need be. Returns the peer it came from and its
+  /// frames.
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError>;
+
+  /// Changes the size above which incoming message frames are refused, for transports that have
+  /// one. It applies to each frame on its own, so a chunk of up to `size` bytes gets through
+  /// whatever blob id and framing come with it.
+  fn set_max_message_size(&mut self, _size: usize) -> Result<(), XactError> {
+    Ok(())
+  }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+  fn send(&mut self, peer: &[u8], frames: &[&[u8]], timeout: Duration) -> Result<(), XactError> {
+    (**self).send(peer, frames, timeout)
+  }
+
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError> {
+    (**self).poll(timeout)
+  }
+
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError> {
+    (**self).recv()
+  }
+
+  fn set_max_message_size(&mut self, size: usize) -> Result<(), XactError> {
+    (**self).set_max_message_size(size)
+  }
+}
+
+fn duration_to_ms(duration: Duration) -> i64 {
+  (duration.as_secs() * 1000 + (duration.subsec_nanos() / 1e6 as u32) as u64) as i64
+}
+
+fn peer_gone() -> XactError {
+  XactError::new(ErrorKind::IO_ERROR(io::ErrorKind::BrokenPipe), "Peer has gone away")
+}
+
+/// A ZMQ DEALER socket on the sending side, or a ROUTER socket on the receiving side.
+///
+/// The ROUTER prefixes its replies with an empty delimiter frame, as DEALER/ROUTER peers built
+/// on other ZMQ bindings expect, and the DEALER strips it off again.
+///
+/// The socket is closed when the transport is dropped, and its context goes with it.
+#[cfg(feature = "zmq")]
+pub struct ZmqTransport {
+  sock: zmq::Socket,
+  routed: bool
+}
+
+#[cfg(feature = "zmq")]
+impl ZmqTransport {
+  /// Connects a DEALER socket to the receiver at `endpoint`.
+  pub fn connect(endpoint: &str) -> Result<ZmqTransport, XactError> {
+    let ctx = zmq::Context::new();
+    let sock = try!(ctx.socket(zmq::DEALER));
+    try!(sock.set_linger(0));
+    try!(sock.connect(endpoint));
+
+    Ok(ZmqTransport { sock: sock, routed: false })
+  }
+
+  /// Binds a ROUTER socket at `endpoint`. Messages bigger than `max_message_size` are dropped,
+  /// and at most `max_queued` messages are buffered before senders are pushed back on.
+  pub fn bind(endpoint: &str, max_message_size: usize, max_queued: usize) -> Result<ZmqTransport, XactError> {
+    let ctx = zmq::Context::new();  // TODO set threads to 2
+    let sock = try!(ctx.socket(zmq::ROUTER));
+    try!(sock.set_linger(0));
+    try!(sock.set_maxmsgsize(max_message_size as i64));
+    try!(sock.set_rcvhwm(max_queued as i32));
+    try!(sock.bind(endpoint));
+    debug!("Bound interface: {}", endpoint);
+
+    Ok(ZmqTransport { sock: sock, routed: true })
+  }
+
+  /// The endpoint the socket was last bound or connected to, with any wildcard port filled in.
+  pub fn last_endpoint(&self) -> Result<String, XactError> {
+    match try!(self.sock.get_last_endpoint()) {
+      Ok(endpoint) => Ok(endpoint),
+      Err(_) => Err(XactError::new(ErrorKind::INVALID_RESPONSE, "Endpoint isn't valid UTF-8"))
+    }
+  }
+}
+
+#[cfg(feature = "zmq")]
+impl Transport for ZmqTransport {
+  fn send(&mut self, peer: &[u8], frames: &[&[u8]], timeout: Duration) -> Result<(), XactError> {
+    let poll_result = try!(zmq::poll(&mut [self.sock.as_poll_item(zmq::POLLOUT)], duration_to_ms(timeout)));
+    if poll_result == 0 {
+      warn!("Poll failed in send().");
+      return Err(XactError::from(zmq::Error::EBUSY));
+    }
+
+    let mut parts: Vec<&[u8]> = vec![];
+    if self.routed {
+      parts.push(peer);
+      parts.push(b"");
+    }
+    parts.extend_from_slice(frames);
+
+    let num_parts = parts.len();
+    for (index, part) in parts.iter().enumerate() {
+      let flags = if index < num_parts - 1 { zmq::SNDMORE|zmq::DONTWAIT } else { zmq::DONTWAIT };
+      try!(self.sock.send(*part, flags));
+    }
+    Ok(())
+  }
+
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError> {
+    let poll_result = try!(zmq::poll(&mut [self.sock.as_poll_item(zmq::POLLIN)], duration_to_ms(timeout)));
+    Ok(poll_result > 0)
+  }
+
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError> {
+    let mut parts = vec![try!(self.sock.recv_bytes(0))];
+    while try!(self.sock.get_rcvmore()) {
+      parts.push(try!(self.sock.recv_bytes(0)));
+    }
+
+    if self.routed {
+      let peer = parts.remove(0);
+      return Ok((peer, parts));
+    }
+
+    if !parts[0].is_empty() {
+      return Err(XactError::new(ErrorKind::INVALID_RESPONSE, "Reply is missing its delimiter frame"));
+    }
+    parts.remove(0);
+    Ok((vec![], parts))
+  }
+
+  fn set_max_message_size(&mut self, size: usize) -> Result<(), XactError> {
+    try!(self.sock.set_maxmsgsize(size as i64));
+    Ok(())
+  }
+}
+
+/// A byte stream that messages can be framed over.
+pub trait FramedStream: Read + Write {
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl FramedStream for TcpStream {
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    TcpStream::set_nonblocking(self, nonblocking)
+  }
+}
+
+#[cfg(unix)]
+impl FramedStream for UnixStream {
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    UnixStream::set_nonblocking(self, nonblocking)
+  }
+}
+
+/// Something that accepts `FramedStream` connections.
+pub trait FramedListener {
+  type Stream: FramedStream;
+
+  fn accept_stream(&self) -> io::Result<Self::Stream>;
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl FramedListener for TcpListener {
+  type Stream = TcpStream;
+
+  fn accept_stream(&self) -> io::Result<TcpStream> {
+    let (stream, _) = try!(self.accept());
+    try!(stream.set_nodelay(true));
+    Ok(stream)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    TcpListener::set_nonblocking(self, nonblocking)
+  }
+}
+
+#[cfg(unix)]
+impl FramedListener for UnixListener {
+  type Stream = UnixStream;
+
+  fn accept_stream(&self) -> io::Result<UnixStream> {
+    self.accept().map(|(stream, _)| stream)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    UnixListener::set_nonblocking(self, nonblocking)
+  }
+}
+
+/// One framed connection. Each message goes over the stream as a 4-byte big-endian frame count,
+/// followed by each frame as a 4-byte big-endian length and then its bytes.
+///
+/// Whatever part of a message the stream won't take yet waits in `outbuf`, and goes out before
+/// anything else is written, so the peer never sees half a message followed by the next one.
+struct Connection<S: FramedStream> {
+  stream: S,
+  inbuf: Vec<u8>,
+  outbuf: Vec<u8>,
+  max_message_size: usize
+}
+
+impl<S: FramedStream> Connection<S> {
+  fn new(stream: S, max_message_size: usize) -> Result<Connection<S>, XactError> {
+    try!(stream.set_nonblocking(true));
+    Ok(Connection { stream: stream, inbuf: vec![], outbuf: vec![], max_message_size: max_message_size })
+  }
+
+  /// Reads whatever has arrived, without blocking. Returns false once the peer has hung up.
+  fn fill(&mut self) -> io::Result<bool> {
+    let mut buf = [0; 65536];
+    loop {
+      match self.stream.read(&mut buf) {
+        Ok(0) => { return Ok(false); },
+        Ok(n) => { self.inbuf.extend_from_slice(&buf[..n]); },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { return Ok(true); },
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => { return Err(e); }
+      }
+    }
+  }
+
+  /// Writes as much of `outbuf` as the stream will take, without blocking.
+  fn flush(&mut self) -> Result<(), XactError> {
+    let mut written = 0;
+    let result = loop {
+      if written == self.outbuf.len() {
+        break Ok(());
+      }
+      match self.stream.write(&self.outbuf[written..]) {
+        Ok(0) => { break Err(peer_gone()); },
+        Ok(n) => { written += n; },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { break Ok(()); },
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => { break Err(XactError::from(e)); }
+      }
+    };
+    self.outbuf.drain(..written);
+    result
+  }
+
+  /// Takes the first complete message off the buffer, if there is one.
+  fn take_message(&mut self) -> Result<Option<Vec<Vec<u8>>>, XactError> {
+    let (num_frames, mut pos) = match read_u32(&self.inbuf, 0) {
+      Some(num_frames) => (num_frames, 4),
+      None => { return Ok(None); }
+    };
+
+    let mut frame_ranges = vec![];
+    for _ in 0..num_frames {
+      let frame_len = match read_u32(&self.inbuf, pos) {
+        Some(frame_len) => frame_len,
+        None => { return Ok(None); }
+      };
+      if frame_len > self.max_message_size {
+        return Err(XactError::new(ErrorKind::INVALID_RESPONSE, "Message frame is too big"));
+      }
+      if self.inbuf.len() < pos + 4 + frame_len {
+        return Ok(None);
+      }
+      frame_ranges.push((pos + 4, pos + 4 + frame_len));
+      pos += 4 + frame_len;
+    }
+
+    let frames = frame_ranges.iter().map(|&(start, end)| self.inbuf[start..end].to_vec()).collect();
+    self.inbuf.drain(..pos);
+    Ok(Some(frames))
+  }
+
+  /// Queues a message and writes what the stream will take of it. If an earlier message is still
+  /// going out, waits up to `timeout` for it to finish first, and fails without queuing anything
+  /// if it doesn't.
+  fn write_message(&mut self, frames: &[&[u8]], timeout: Duration) -> Result<(), XactError> {
+    let mut buf = Vec::with_capacity(4 + frames.iter().map(|f| 4 + f.len()).sum::<usize>());
+    try!(write_u32(&mut buf, frames.len()));
+    for frame in frames {
+      try!(write_u32(&mut buf, frame.len()));
+      buf.extend_from_slice(frame);
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+      try!(self.flush());
+      if self.outbuf.is_empty() {
+        break;
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(XactError::new(ErrorKind::TIMEOUT, "Timed out writing to the stream"));
+      }
+      thread::sleep(cmp::min(deadline - now, Duration::from_millis(STREAM_POLL_MS)));
+    }
+
+    self.outbuf = buf;
+    self.flush()
+  }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<usize> {
+  if buf.len() < pos + 4 {
+    return None;
+  }
+  Some(buf[pos..pos + 4].iter().fold(0, |n, &b| (n << 8) | b as usize))
+}
+
+fn write_u32(buf: &mut Vec<u8>, n: usize) -> Result<(), XactError> {
+  if n as u64 > u32::MAX as u64 {
+    return Err(XactError::new(ErrorKind::IO_ERROR(io::ErrorKind::InvalidInput), "Frame is too big for the stream framing"));
+  }
+  buf.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+  Ok(())
+}
+
+/// The sending side of a framed connection over a plain TCP or Unix socket.
+pub struct StreamTransport<S: FramedStream> {
+  conn: Connection<S>,
+  inbox: VecDeque<Vec<Vec<u8>>>
+}
+
+impl StreamTransport<TcpStream> {
+  pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> Result<StreamTransport<TcpStream>, XactError> {
+    let stream = try!(TcpStream::connect(addr));
+    try!(stream.set_nodelay(true));
+    StreamTransport::new(stream)
+  }
+}
+
+#[cfg(unix)]
+impl StreamTransport<UnixStream> {
+  pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<StreamTransport<UnixStream>, XactError> {
+    StreamTransport::new(try!(UnixStream::connect(path)))
+  }
+}
+
+impl<S: FramedStream> StreamTransport<S> {
+  pub fn new(stream: S) -> Result<StreamTransport<S>, XactError> {
+    Ok(StreamTransport { conn: try!(Connection::new(stream, usize::MAX / 2)), inbox: VecDeque::new() })
+  }
+}
+
+impl<S: FramedStream> Transport for StreamTransport<S> {
+  fn send(&mut self, _peer: &[u8], frames: &[&[u8]], timeout: Duration) -> Result<(), XactError> {
+    self.conn.write_message(frames, timeout)
+  }
+
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+      try!(self.conn.flush());
+      let open = try!(self.conn.fill());
+      while let Some(frames) = try!(self.conn.take_message()) {
+        self.inbox.push_back(frames);
+      }
+      if !self.inbox.is_empty() {
+        return Ok(true);
+      }
+      if !open {
+        return Err(peer_gone());
+      }
+
+      let now = Instant::now();
+      if now >= deadline {
+        return Ok(false);
+      }
+      thread::sleep(cmp::min(deadline - now, Duration::from_millis(STREAM_POLL_MS)));
+    }
+  }
+
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError> {
+    while !try!(self.poll(Duration::from_secs(1))) {}
+    Ok((vec![], self.inbox.pop_front().unwrap()))
+  }
+}
+
+/// The receiving side of framed connections over plain TCP or Unix sockets. Each connection is
+/// a separate peer.
+pub struct StreamListener<L: FramedListener> {
+  listener: L,
+  conns: HashMap<Vec<u8>, Connection<L::Stream>>,
+  next_peer: usize,
+  max_message_size: usize,
+  inbox: VecDeque<(Vec<u8>, Vec<Vec<u8>>)>
+}
+
+impl StreamListener<TcpListener> {
+  /// Listens on `addr`. Connections that send a message frame bigger than `max_message_size` are
+  /// dropped, as ZMQ drops them.
+  pub fn bind_tcp<A: ToSocketAddrs>(addr: A, max_message_size: usize) -> Result<StreamListener<TcpListener>, XactError> {
+    StreamListener::new(try!(TcpListener::bind(addr)), max_message_size)
+  }
+}
+
+#[cfg(unix)]
+impl StreamListener<UnixListener> {
+  /// Listens on the socket file at `path`, which must not already exist.
+  pub fn bind_unix<P: AsRef<Path>>(path: P, max_message_size: usize) -> Result<StreamListener<UnixListener>, XactError> {
+    StreamListener::new(try!(UnixListener::bind(path)), max_message_size)
+  }
+}
+
+impl<L: FramedListener> StreamListener<L> {
+  pub fn new(listener: L, max_message_size: usize) -> Result<StreamListener<L>, XactError> {
+    try!(listener.set_nonblocking(true));
+    Ok(StreamListener {
+      listener: listener,
+      conns: HashMap::new(),
+      next_peer: 0,
+      max_message_size: max_message_size,
+      inbox: VecDeque::new()
+    })
+  }
+
+  fn accept_all(&mut self) {
+    loop {
+      match self.listener.accept_stream() {
+        Ok(stream) => {
+          match Connection::new(stream, self.max_message_size) {
+            Ok(conn) => {
+              let peer = int_to_bytes(self.next_peer);
+              self.next_peer += 1;
+              debug!("Accepted connection from peer {:?}", peer);
+              self.conns.insert(peer, conn);
+            },
+            Err(e) => { debug!("Unable to set up connection: {}", e); }
+          }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { return; },
+        Err(e) => {
+          debug!("Error accepting connection: {:?}", e);
+          return;
+        }
+      }
+    }
+  }
+
+  /// Writes out what's still waiting to go, reads whatever has arrived on every connection, and
+  /// drops the ones that have hung up or misbehaved.
+  fn read_all(&mut self) {
+    let mut dead_peers = vec![];
+    for (peer, conn) in self.conns.iter_mut() {
+      if let Err(e) = conn.flush() {
+        debug!("Error writing to peer {:?}: {}", peer, e);
+        dead_peers.push(peer.clone());
+        continue;
+      }
+      let open = match conn.fill() {
+        Ok(open) => open,
+        Err(e) => {
+          debug!("Error reading from peer {:?}: {:?}", peer, e);
+          false
+        }
+      };
+      loop {
+        match conn.take_message() {
+          Ok(Some(frames)) => { self.inbox.push_back((peer.clone(), frames)); },
+          Ok(None) => { break; },
+          Err(e) => {
+            debug!("Dropping peer {:?}: {}", peer, e);
+            dead_peers.push(peer.clone());
+            break;
+          }
+        }
+      }
+      if !open {
+        dead_peers.push(peer.clone());
+      }
+    }
+
+    for peer in dead_peers {
+      self.conns.remove(&peer);
+    }
+  }
+}
+
+impl<L: FramedListener> Transport for StreamListener<L> {
+  /// Never waits, so one slow peer can't hold up replies to the others. If the peer hasn't taken
+  /// the last reply yet, this one fails with `TIMEOUT` straight away.
+  fn send(&mut self, peer: &[u8], frames: &[&[u8]], _timeout: Duration) -> Result<(), XactError> {
+    match self.conns.get_mut(peer) {
+      Some(conn) => conn.write_message(frames, Duration::from_secs(0)),
+      None => Err(peer_gone())
+    }
+  }
+
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+      self.accept_all();
+      self.read_all();
+      if !self.inbox.is_empty() {
+        return Ok(true);
+      }
+
+      let now = Instant::now();
+      if now >= deadline {
+        return Ok(false);
+      }
+      thread::sleep(cmp::min(deadline - now, Duration::from_millis(STREAM_POLL_MS)));
+    }
+  }
+
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError> {
+    while !try!(self.poll(Duration::from_secs(1))) {}
+    Ok(self.inbox.pop_front().unwrap())
+  }
+
+  fn set_max_message_size(&mut self, size: usize) -> Result<(), XactError> {
+    self.max_message_size = size;
+    for conn in self.conns.values_mut() {
+      conn.max_message_size = size;
+    }
+    Ok(())
+  }
+}
+
+type ChannelMessage = (Vec<u8>, Vec<Vec<u8>>);
+
+/// An in-process transport over channels, mostly for tests.
+///
+/// `ChannelTransport::bind()` makes the receiving side, and `connect()` on its `ChannelConnector`
+/// makes sending sides for it, which can be moved to other threads.
+pub struct ChannelTransport {
+  rx: Receiver<ChannelMessage>,
+  route: ChannelRoute,
+  pending: Option<ChannelMessage>
+}
+
+enum ChannelRoute {
+  /// The receiving side, which can reply to any connected peer.
+  Bound(Arc<Mutex<HashMap<Vec<u8>, Sender<ChannelMessage>>>>),
+  /// A sending side, known to the receiving side by `id`.
+  Connected { id: Vec<u8>, tx: Sender<ChannelMessage> }
+}
+
+/// Makes new connections to a bound `ChannelTransport`.
+#[derive(Clone)]
+pub struct ChannelConnector {
+  tx: Sender<ChannelMessage>,
+  peers: Arc<Mutex<HashMap<Vec<u8>, Sender<ChannelMessage>>>>
+}
+
+impl ChannelTransport {
+  pub fn bind() -> (ChannelTransport, ChannelConnector) {
+    let (tx, rx) = channel();
+    let peers = Arc::new(Mutex::new(HashMap::new()));
+    let transport = ChannelTransport { rx: rx, route: ChannelRoute::Bound(peers.clone()), pending: None };
+    (transport, ChannelConnector { tx: tx, peers: peers })
+  }
+}
+
+impl ChannelConnector {
+  pub fn connect(&self) -> ChannelTransport {
+    let (tx, rx) = channel();
+    let id = {
+      let mut peers = self.peers.lock().unwrap();
+      let id = int_to_bytes(peers.len());
+      peers.insert(id.clone(), tx);
+      id
+    };
+    ChannelTransport {
+      rx: rx,
+      route: ChannelRoute::Connected { id: id, tx: self.tx.clone() },
+      pending: None
+    }
+  }
+}
+
+impl Transport for ChannelTransport {
+  fn send(&mut self, peer: &[u8], frames: &[&[u8]], _timeout: Duration) -> Result<(), XactError> {
+    let frames = frames.iter().map(|f| f.to_vec()).collect();
+    let result = match self.route {
+      ChannelRoute::Bound(ref peers) => {
+        match peers.lock().unwrap().get(peer) {
+          Some(tx) => tx.send((vec![], frames)),
+          None => { return Err(peer_gone()); }
+        }
+      },
+      ChannelRoute::Connected { ref id, ref tx } => tx.send((id.clone(), frames))
+    };
+    result.map_err(|_| peer_gone())
+  }
+
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError> {
+    if self.pending.is_some() {
+      return Ok(true);
+    }
+    match self.rx.recv_timeout(timeout) {
+      Ok(msg) => {
+        self.pending = Some(msg);
+        Ok(true)
+      },
+      Err(RecvTimeoutError::Timeout) => Ok(false),
+      Err(RecvTimeoutError::Disconnected) => Err(peer_gone())
+    }
+  }
+
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError> {
+    if let Some(msg) = self.pending.take() {
+      return Ok(msg);
+    }
+    self.rx.recv().map_err(|_| peer_gone())
+  }
+}
+
+/// What a `FaultyTransport` does to outgoing messages. Each is the chance, between 0 and 1, that
+/// it happens to a given message; at most one happens to each.
+#[derive(Clone, Copy, Debug)]
+pub struct Faults {
+  /// The message is never sent.
+  pub drop: f64,
+  /// A byte of the message's biggest frame, usually chunk data, is flipped.
+  pub corrupt: f64,
+  /// The message is sent twice.
+  pub duplicate: f64,
+  /// The message is held back for up to `max_delay`.
+  pub delay: f64,
+  /// The message is held back until the next one has been sent, or `max_delay` has passed.
+  pub reorder: f64,
+  pub max_delay: Duration
+}
+
+impl Faults {
+  pub fn none() -> Faults {
+    Faults { drop: 0.0, corrupt: 0.0, duplicate: 0.0, delay: 0.0, reorder: 0.0, max_delay: Duration::from_millis(0) }
+  }
+}
+
+/// A message a `FaultyTransport` is holding back.
+struct HeldMessage {
+  due: Instant,
+  after_next_send: bool,
+  peer: Vec<u8>,
+  frames: Vec<Vec<u8>>
+}
+
+/// Wraps another transport and drops, corrupts, duplicates, delays and reorders what is sent over
+/// it, for testing how both ends cope with a bad connection.
+///
+/// Only outgoing messages are tampered with, so to mangle both directions wrap the transports at
+/// both ends. The faults are drawn from a simple RNG seeded with `seed`, so a failing run can be
+/// replayed. Held-back messages go out during later calls to `send()` and `poll()`.
+pub struct FaultyTransport<T: Transport> {
+  inner: T,
+  faults: Faults,
+  rng: XorShift,
+  held: Vec<HeldMessage>
+}
+
+impl<T: Transport> FaultyTransport<T> {
+  pub fn new(inner: T, faults: Faults, seed: u64) -> FaultyTransport<T> {
+    FaultyTransport { inner: inner, faults: faults, rng: XorShift::new(seed), held: vec![] }
+  }
+
+  fn random_delay(&mut self) -> Duration {
+    let max_ms = duration_to_ms(self.faults.max_delay) as f64;
+    Duration::from_millis((self.rng.next_f64() * max_ms) as u64)
+  }
+
+  fn hold(&mut self, peer: &[u8], frames: &[&[u8]], delay: Duration, after_next_send: bool) {
+    self.held.push(HeldMessage {
+      due: Instant::now() + delay,
+      after_next_send: after_next_send,
+      peer: peer.to_vec(),
+      frames: frames.iter().map(|f| f.to_vec()).collect()
+    });
+  }
+
+  /// Sends the held messages that are due. They were going to arrive late anyway, so errors
+  /// sending them are only logged.
+  fn release(&mut self, sent_another: bool, timeout: Duration) {
+    let now = Instant::now();
+    let (due, held): (Vec<HeldMessage>, Vec<HeldMessage>) = self.held.drain(..).partition(|msg| {
+      msg.due <= now || (sent_another && msg.after_next_send)
+    });
+    self.held = held;
+
+    for msg in due {
+      let frames: Vec<&[u8]> = msg.frames.iter().map(|f| f.as_slice()).collect();
+      if let Err(e) = self.inner.send(&msg.peer, &frames, timeout) {
+        debug!("Error sending held-back message: {}", e);
+      }
+    }
+  }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+  fn send(&mut self, peer: &[u8], frames: &[&[u8]], timeout: Duration) -> Result<(), XactError> {
+    self.release(false, timeout);
+
+    let faults = self.faults;
+    let mut roll = self.rng.next_f64();
+    if roll < faults.drop {
+      debug!("Dropping message.");
+      return Ok(());
+    }
+    roll -= faults.drop;
+    if roll < faults.corrupt {
+      debug!("Corrupting message.");
+      let mut frames: Vec<Vec<u8>> = frames.iter().map(|f| f.to_vec()).collect();
+      if let Some(frame) = frames.iter_mut().max_by_key(|f| f.len()) {
+        if !frame.is_empty() {
+          let index = (self.rng.next_u64() % frame.len() as u64) as usize;
+          frame[index] ^= 0xff;
+        }
+      }
+      let frames: Vec<&[u8]> = frames.iter().map(|f| f.as_slice()).collect();
+      try!(self.inner.send(peer, &frames, timeout));
+      self.release(true, timeout);
+      return Ok(());
+    }
+    roll -= faults.corrupt;
+    if roll < faults.duplicate {
+      debug!("Duplicating message.");
+      try!(self.inner.send(peer, frames, timeout));
+    } else {
+      roll -= faults.duplicate;
+      if roll < faults.delay {
+        debug!("Delaying message.");
+        let delay = self.random_delay();
+        self.hold(peer, frames, delay, false);
+        return Ok(());
+      }
+      roll -= faults.delay;
+      if roll < faults.reorder {
+        debug!("Holding message back to reorder it.");
+        self.hold(peer, frames, faults.max_delay, true);
+        return Ok(());
+      }
+    }
+
+    try!(self.inner.send(peer, frames, timeout));
+    self.release(true, timeout);
+    Ok(())
+  }
+
+  fn poll(&mut self, timeout: Duration) -> Result<bool, XactError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+      self.release(false, timeout);
+
+      let now = Instant::now();
+      let mut slice = if deadline > now { deadline - now } else { Duration::new(0, 0) };
+      if let Some(due) = self.held.iter().map(|msg| msg.due).min() {
+        slice = cmp::min(slice, if due > now { due - now } else { Duration::new(0, 0) });
+      }
+      if try!(self.inner.poll(slice)) {
+        return Ok(true);
+      }
+      if Instant::now() >= deadline {
+        return Ok(false);
+      }
+    }
+  }
+
+  fn recv(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), XactError> {
+    self.inner.recv()
+  }
+
+  fn set_max_message_size(&mut self, size: usize) -> Result<(), XactError> {
+    self.inner.set_max_message_size(size)
+  }
+}
+
+/// A xorshift64* generator: not random enough for anything but tests, which is all it's for.
+struct XorShift {
+  state: u64
+}
+
+impl XorShift {
+  fn new(seed: u64) -> XorShift {
+    // The state must never be zero.
+    XorShift { state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.state ^= self.state >> 12;
+    self.state ^= self.state << 25;
+    self.state ^= self.state >> 27;
+    self.state.wrapping_mul(0x2545f4914f6cdd1d)
+  }
+
+  /// A number in [0, 1).
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
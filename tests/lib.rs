@@ -1,60 +1,2436 @@
-#![feature(rustc_private)]
-
 extern crate xact;
+#[cfg(feature = "zmq")]
+extern crate zmq;
 
-use xact::sender::{send_binary_blob};
-use xact::receiver::{BlobReceiver, BasicBlobReceiverBehavior, DEFAULT_CHUNK_SIZE, STOP};
+#[cfg(feature = "zmq")]
+use xact::sender::{send_binary_blob, send_binary_blob_with_retry, send_reader, RetryPolicy};
+use xact::sender::{ProgressEvent, Proposal, SendHandle, SenderAction, SenderMachine, Session};
+#[cfg(feature = "zmq")]
+use xact::receiver::DEFAULT_CHUNK_SIZE;
+use xact::receiver::{Blob, BlobReceiver, BlobReceiverBehavior, BasicBlobReceiverBehavior, ConsReply, ReceiverEvent,
+                     ReceiverMachine, STOP};
+#[cfg(feature = "zmq")]
+use xact::sink::FileSinkBehavior;
+use xact::sink::{BlobSink, FileSink};
+use xact::protocol::Message;
+use xact::transport::{ChannelTransport, Faults, FaultyTransport, StreamListener, StreamTransport, Transport};
+use xact::hash;
+use xact::flow::CreditWindow;
+use xact::{capabilities, AbortReason, ErrorKind, FailReason, PeerInfo, PROTOCOL_VERSION};
 
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "zmq")]
 use std::error::Error;  // So we can use e.description()
+#[cfg(feature = "zmq")]
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::io::{self, Read};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::thread;
-use std::time::Duration;
-use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
 #[test]
 #[ignore]
+#[cfg(feature = "zmq")]
 fn send_small_string() {
   match send_binary_blob("tcp://127.0.0.1:1234", "msg-0", "ermahgerd".as_bytes(), Duration::from_millis(2000), false, |s| { info!("{}", s) }) {
     Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
     Err(e) => {
       error!("Error: {}", xact::XactError::description(&e));
-      panic!(e)
+      panic!("{}", e)
     }
   };
 }
 
 #[test]
 #[ignore]
+#[cfg(feature = "zmq")]
 fn send_big_vec() {
-  match send_binary_blob("tcp://127.0.0.1:1234", "msg-1", vec![0x2a as u8; 1e8 as usize].as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+  match send_binary_blob("tcp://127.0.0.1:1234", "msg-1", vec![0x2a_u8; 1e8 as usize].as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
     Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
     Err(e) => {
       error!("Error: {}", xact::XactError::description(&e));
-      panic!(e)
+      panic!("{}", e)
     }
   };
 }
 
+/// Runs a receiver on a free local port until `STOP` arrives on `stop_rx`. Returns the endpoint to
+/// send to, and the receiver's thread.
+#[cfg(feature = "zmq")]
+fn zmq_receiver<B>(chunk_size: usize, behavior: B, stop_rx: Receiver<bool>) -> (String, thread::JoinHandle<()>)
+                   where B: BlobReceiverBehavior + Send + 'static {
+  let (endpoint_tx, endpoint_rx) = channel();
+  let handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::new("tcp://127.0.0.1:*", chunk_size, behavior).unwrap();
+    endpoint_tx.send(receiver.bind_address.clone()).unwrap();
+    receiver.run(stop_rx);
+  });
+  (endpoint_rx.recv().unwrap(), handle)
+}
+
+/// A ROUTER socket on a free local port, for playing the receiver by hand. Returns the socket and
+/// the endpoint to send to.
+#[cfg(feature = "zmq")]
+fn fake_receiver_socket() -> (zmq::Socket, String) {
+  let sock = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+  sock.bind("tcp://127.0.0.1:*").unwrap();
+  let endpoint = sock.get_last_endpoint().unwrap().unwrap();
+  (sock, endpoint)
+}
+
 #[test]
+#[cfg(feature = "zmq")]
 fn recv_big_vec() {
   let (tx, rx) = channel();
 
-  let recv_handle = thread::spawn(move || {
-    let behavior = BasicBlobReceiverBehavior {};
-    let mut receiver = BlobReceiver::new("tcp://*:1234", DEFAULT_CHUNK_SIZE, behavior).unwrap();
-    receiver.run(rx);
+  let (endpoint, recv_handle) = zmq_receiver(DEFAULT_CHUNK_SIZE, BasicBlobReceiverBehavior {}, rx);
+
+  match send_binary_blob(&endpoint, "msg-1", vec![0x2a_u8; 1e8 as usize].as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+    Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
+    Err(e) => {
+      error!("Error: {}", xact::XactError::description(&e));
+      panic!("{}", e)
+    }
+  };
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn recv_from_reader() {
+  let (tx, rx) = channel();
+
+  let (endpoint, recv_handle) = zmq_receiver(DEFAULT_CHUNK_SIZE, BasicBlobReceiverBehavior {}, rx);
+
+  let data_length = 5e7 as usize;
+  let reader = io::repeat(0x2a).take(data_length as u64);
+  match send_reader(&endpoint, "msg-2", reader, data_length, Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+    Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
+    Err(e) => {
+      error!("Error: {}", xact::XactError::description(&e));
+      panic!("{}", e)
+    }
+  };
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[cfg(feature = "zmq")]
+struct CountingSink {
+  total: usize,
+  done_tx: Sender<(usize, bool)>
+}
+
+#[cfg(feature = "zmq")]
+impl BlobSink for CountingSink {
+  fn on_start(&mut self, _id: &[u8], _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_chunk(&mut self, offset: usize, bytes: &[u8]) -> bool {
+    assert_eq!(offset, self.total);
+    assert!(bytes.iter().all(|&b| b == 0x2a));
+    self.total += bytes.len();
+    true
+  }
+
+  fn on_finish(&mut self, hash_ok: bool) -> bool {
+    self.done_tx.send((self.total, hash_ok)).unwrap();
+    true
+  }
+}
+
+#[cfg(feature = "zmq")]
+struct CountingBehavior {
+  done_tx: Sender<(usize, bool)>
+}
+
+#[cfg(feature = "zmq")]
+impl BlobReceiverBehavior for CountingBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], array: &[u8]) {
+    assert!(array.is_empty());
+  }
+
+  fn new_sink(&mut self, _id: &[u8], _data_size: usize) -> Option<Box<BlobSink>> {
+    Some(Box::new(CountingSink { total: 0, done_tx: self.done_tx.clone() }))
+  }
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn recv_into_sink() {
+  let (tx, rx) = channel();
+  let (done_tx, done_rx) = channel();
+
+  let (endpoint, recv_handle) = zmq_receiver(1e6 as usize, CountingBehavior { done_tx: done_tx }, rx);
+
+  let data_length = 2.5e6 as usize;
+  match send_binary_blob(&endpoint, "msg-3", vec![0x2a_u8; data_length].as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+    Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
+    Err(e) => {
+      error!("Error: {}", xact::XactError::description(&e));
+      panic!("{}", e)
+    }
+  };
+
+  assert_eq!(done_rx.recv().unwrap(), (data_length, true));
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn recv_to_file() {
+  let (tx, rx) = channel();
+  let dir = env::temp_dir().join("xact-recv-to-file");
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+
+  let (endpoint, recv_handle) = zmq_receiver(1e6 as usize, FileSinkBehavior::new(dir.clone()), rx);
+
+  let data = vec![0x2a_u8; 2.5e6 as usize];
+  match send_binary_blob(&endpoint, "msg-4", data.as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+    Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
+    Err(e) => {
+      error!("Error: {}", xact::XactError::description(&e));
+      panic!("{}", e)
+    }
+  };
+
+  let mut written = vec![];
+  fs::File::open(dir.join("msg-4")).unwrap().read_to_end(&mut written).unwrap();
+  assert_eq!(written, data);
+  assert_eq!(partial_files(&dir, "msg-4"), Vec::<String>::new());
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+/// The partial files a `FileSink` has left in `dir` for `blob_id`.
+fn partial_files(dir: &Path, blob_id: &str) -> Vec<String> {
+  fs::read_dir(dir).unwrap()
+    .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+    .filter(|name| name.starts_with(&format!("{}.", blob_id)) && name.ends_with(".partial"))
+    .collect()
+}
+
+/// Reader that fails after yielding `limit` bytes.
+struct FailingReader<'a> {
+  data: &'a [u8],
+  limit: usize
+}
+
+impl<'a> Read for FailingReader<'a> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.limit == 0 {
+      return Err(io::Error::other("disk died"));
+    }
+    let n = std::cmp::min(self.limit, buf.len());
+    let n = try!((&mut self.data).read(&mut buf[..n]));
+    self.limit -= n;
+    Ok(n)
+  }
+}
+
+#[cfg(feature = "zmq")]
+/// Reader that hangs after yielding `limit` bytes, to simulate a sender dying partway through
+/// without a word to the receiver. It says so on `stalled`, and fails once `unstall` hangs up.
+struct StallingReader {
+  data: io::Cursor<Vec<u8>>,
+  limit: usize,
+  stalled: Sender<()>,
+  unstall: Receiver<()>
+}
+
+#[cfg(feature = "zmq")]
+impl Read for StallingReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.limit == 0 {
+      self.stalled.send(()).unwrap();
+      let _ = self.unstall.recv();
+      return Err(io::Error::other("sender died"));
+    }
+    let n = std::cmp::min(self.limit, buf.len());
+    let n = try!(self.data.read(&mut buf[..n]));
+    self.limit -= n;
+    Ok(n)
+  }
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn resume_after_failure() {
+  let (tx, rx) = channel();
+  let dir = env::temp_dir().join("xact-resume");
+  fs::create_dir_all(&dir).unwrap();
+  let _ = fs::remove_file(dir.join("msg-5"));
+
+  let (endpoint, recv_handle) = zmq_receiver(1e6 as usize, FileSinkBehavior::new(dir.clone()), rx);
+
+  let data: Vec<u8> = (0..2.5e6 as usize).map(|i| (i % 251) as u8).collect();
+
+  let (token_tx, token_rx) = channel();
+  let (stalled_tx, stalled_rx) = channel();
+  let (unstall_tx, unstall_rx) = channel();
+  let reader = StallingReader { data: io::Cursor::new(data.clone()), limit: 1.5e6 as usize, stalled: stalled_tx, unstall: unstall_rx };
+  let data_length = data.len();
+  let dead_endpoint = endpoint.clone();
+  let dead_sender = thread::spawn(move || {
+    let mut session = Session::connect(&dead_endpoint, Duration::from_millis(20000)).unwrap();
+    session.send_reader("msg-5", reader, data_length, Duration::from_millis(20000), false, move |event| {
+      if let ProgressEvent::Accepted { resume_token, .. } = event {
+        token_tx.send(resume_token.unwrap()).unwrap();
+      }
+    })
+  });
+  let token = token_rx.recv().unwrap();
+  stalled_rx.recv().unwrap();
+  assert!(!dir.join("msg-5").exists());
+
+  // Picked up from a new connection, as after a restart.
+  let mut session = Session::connect(&endpoint, Duration::from_millis(20000)).unwrap();
+  session.set_resume_token("msg-5", &token);
+  match session.resume_reader("msg-5", data.as_slice(), data.len(), Duration::from_millis(20000), false, |e| { info!("{:?}", e) }) {
+    Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
+    Err(e) => {
+      error!("Error: {}", xact::XactError::description(&e));
+      panic!("{}", e)
+    }
+  };
+
+  let mut written = vec![];
+  fs::File::open(dir.join("msg-5")).unwrap().read_to_end(&mut written).unwrap();
+  assert_eq!(written, data);
+
+  drop(unstall_tx);
+  assert!(dead_sender.join().unwrap().is_err());
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn session_sends_many_blobs() {
+  let (tx, rx) = channel();
+
+  let (endpoint, recv_handle) = zmq_receiver(1e6 as usize, BasicBlobReceiverBehavior {}, rx);
+
+  let mut session = Session::connect(&endpoint, Duration::from_millis(2000)).unwrap();
+  for i in 0..20 {
+    let blob_id = format!("msg-6-{}", i);
+    let data = vec![i as u8; 1000 * i];
+    let events = RefCell::new(vec![]);
+    match session.send(&blob_id, data.as_slice(), Duration::from_millis(2000), false, |e| { events.borrow_mut().push(e) }) {
+      Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
+      Err(e) => {
+        error!("Error: {}", xact::XactError::description(&e));
+        panic!("{}", e)
+      }
+    };
+
+    // Blobs smaller than a chunk go in one chunk of their own size.
+    let expected_chunk_size = data.len().clamp(1, 1000000);
+    let events = events.into_inner();
+    match events.as_slice() {
+      [ProgressEvent::Connected, ProgressEvent::Accepted { chunk_size, .. }, .., ProgressEvent::Finalizing, ProgressEvent::Done { .. }]
+        if *chunk_size == expected_chunk_size => {},
+      _ => { panic!("Unexpected progress events: {:?}", events); }
+    }
+  }
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+/// Replies to consistent transactions with the sum of the blob's bytes, computed on another thread.
+struct SummingBehavior;
+
+impl BlobReceiverBehavior for SummingBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], _array: &[u8]) {
+    panic!("Expected a consistent transaction.");
+  }
+
+  fn on_consistent_complete(&mut self, _id: &[u8], array: &[u8], reply: ConsReply) {
+    let array = array.to_vec();
+    thread::spawn(move || {
+      let sum: u64 = array.iter().map(|&b| b as u64).sum();
+      reply.send(format!("{}", sum).into_bytes());
+    });
+  }
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn consistent_send() {
+  let (tx, rx) = channel();
+
+  let (endpoint, recv_handle) = zmq_receiver(1e6 as usize, SummingBehavior, rx);
+
+  let data = vec![3_u8; 2.5e6 as usize];
+  match send_binary_blob(&endpoint, "msg-7", data.as_slice(), Duration::from_millis(20000), true, |s| { info!("{}", s) }) {
+    Ok(result_bytes) => { assert_eq!(result_bytes, b"7500000"); },
+    Err(e) => {
+      error!("Error: {}", xact::XactError::description(&e));
+      panic!("{}", e)
+    }
+  };
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn bad_pong_is_protocol_violation() {
+  let (sock, endpoint) = fake_receiver_socket();
+  let fake_handle = thread::spawn(move || {
+    let sender_id = sock.recv_bytes(0).unwrap();
+    while sock.get_rcvmore().unwrap() {
+      sock.recv_bytes(0).unwrap();
+    }
+    sock.send_multipart([sender_id.as_slice(), b"", b"PANG"], 0).unwrap();
+  });
+
+  match send_binary_blob(&endpoint, "msg-8", b"ermahgerd", Duration::from_millis(2000), false, |s| { info!("{}", s) }) {
+    Ok(_) => { panic!("Expected a protocol violation."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::PROTOCOL_VIOLATION { phase, .. } => { assert_eq!(phase, "ping"); },
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  };
+
+  fake_handle.join().unwrap();
+}
+
+/// Minimal receiver that answers the first `fails` ENDs with FAIL. Returns the number of ENDs seen
+/// once a blob has been accepted, along with the endpoint to send to.
+#[cfg(feature = "zmq")]
+fn flaky_receiver(fails: usize) -> (String, thread::JoinHandle<usize>) {
+  let (sock, endpoint) = fake_receiver_socket();
+  let handle = thread::spawn(move || {
+    let mut ends = 0;
+    loop {
+      let mut parts = vec![sock.recv_bytes(0).unwrap()];
+      while sock.get_rcvmore().unwrap() {
+        parts.push(sock.recv_bytes(0).unwrap());
+      }
+
+      let sender_id = parts[0].as_slice();
+      match parts[1].as_slice() {
+        b"PING" => { sock.send_multipart([sender_id, b"", b"PONG", b"2"], 0).unwrap(); },
+        b"START" => {
+          sock.send_multipart([sender_id, b"", b"GOGO", &parts[2], b"1000"], 0).unwrap();
+          sock.send_multipart([sender_id, b"", b"TOKEN", &parts[2]], 0).unwrap();
+        },
+        b"END" => {
+          ends += 1;
+          if ends <= fails {
+            sock.send_multipart([sender_id, b"", b"FAIL", &parts[2], b"HASH_MISMATCH"], 0).unwrap();
+          } else {
+            sock.send_multipart([sender_id, b"", b"OK", &parts[2], b"Great success"], 0).unwrap();
+            return ends;
+          }
+        },
+        _ => {}
+      }
+    }
   });
+  (endpoint, handle)
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn fail_is_retried() {
+  let (endpoint, fake_handle) = flaky_receiver(2);
 
-  match send_binary_blob("tcp://127.0.0.1:1234", "msg-1", vec![0x2a as u8; 1e8 as usize].as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+  let retry = RetryPolicy { max_retries: 2, backoff: Duration::from_millis(10) };
+  match send_binary_blob_with_retry(&endpoint, "msg-9", b"ermahgerd", Duration::from_millis(2000), false, retry, |s| { info!("{}", s) }) {
     Ok(result_bytes) => { info!("Result: {:?}", result_bytes); },
     Err(e) => {
       error!("Error: {}", xact::XactError::description(&e));
-      panic!(e)
+      panic!("{}", e)
+    }
+  };
+
+  assert_eq!(fake_handle.join().unwrap(), 3);
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn fail_reports_hash_mismatch() {
+  let (endpoint, fake_handle) = flaky_receiver(1);
+
+  match send_binary_blob(&endpoint, "msg-10", b"ermahgerd", Duration::from_millis(2000), false, |s| { info!("{}", s) }) {
+    Ok(_) => { panic!("Expected a hash mismatch."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::HASH_MISMATCH => { assert_eq!(e.msg(), "Hash mismatch"); },
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  };
+
+  // Let the fake receiver finish.
+  send_binary_blob(&endpoint, "msg-10", b"ermahgerd", Duration::from_millis(2000), false, |s| { info!("{}", s) }).unwrap();
+  fake_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn cancel_sends_abort() {
+  let (sock, endpoint) = fake_receiver_socket();
+  let fake_handle = thread::spawn(move || {
+    loop {
+      let mut parts = vec![sock.recv_bytes(0).unwrap()];
+      while sock.get_rcvmore().unwrap() {
+        parts.push(sock.recv_bytes(0).unwrap());
+      }
+
+      let sender_id = parts[0].as_slice();
+      match parts[1].as_slice() {
+        b"PING" => { sock.send_multipart([sender_id, b"", b"PONG", b"2", b"abort"], 0).unwrap(); },
+        // Accept the blob, but never hand out any TOKENs.
+        b"START" => { sock.send_multipart([sender_id, b"", b"GOGO", &parts[2], b"1000"], 0).unwrap(); },
+        b"ABORT" => { return parts[2].clone(); },
+        _ => {}
+      }
+    }
+  });
+
+  let mut session = Session::connect(&endpoint, Duration::from_millis(2000)).unwrap();
+  let handle = session.handle();
+  thread::spawn(move || {
+    thread::sleep(Duration::from_millis(200));
+    handle.cancel();
+  });
+
+  match session.send("msg-11", b"ermahgerd", Duration::from_millis(20000), false, |e| { info!("{:?}", e) }) {
+    Ok(_) => { panic!("Expected the send to be cancelled."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::CANCELLED => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  };
+
+  assert_eq!(fake_handle.join().unwrap(), b"msg-11");
+}
+
+#[test]
+fn cancel_before_send_cancels_it() {
+  let (tx, rx) = channel();
+  let (transport, connector) = ChannelTransport::bind();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::with_transport(transport, 1000, BasicBlobReceiverBehavior);
+    receiver.run(rx);
+  });
+
+  let mut session = Session::connect_with(move || Ok(connector.connect()), Duration::from_millis(2000)).unwrap();
+  let handle = SendHandle::new();
+  session.set_handle(handle.clone());
+  handle.cancel();
+  match session.send("msg-63", b"ermahgerd", Duration::from_millis(2000), false, |_| {}) {
+    Ok(_) => { panic!("Expected the send to be cancelled."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::CANCELLED => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  }
+  // The cancel was used up by the send it cancelled.
+  session.send("msg-65", b"ermahgerd", Duration::from_millis(2000), false, |_| {}).unwrap();
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+/// Reports why the receiver was told to drop a blob.
+struct AbortWatchingBehavior {
+  aborted: Sender<AbortReason>
+}
+
+impl BlobReceiverBehavior for AbortWatchingBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], _array: &[u8]) {
+    panic!("Blob should have been aborted.");
+  }
+
+  fn on_event(&mut self, event: ReceiverEvent) {
+    if let ReceiverEvent::BlobAborted { reason, .. } = event {
+      self.aborted.send(reason).unwrap();
+    }
+  }
+}
+
+#[test]
+fn read_error_sends_abort() {
+  let (tx, rx) = channel();
+  let (aborted_tx, aborted_rx) = channel();
+  let (transport, connector) = ChannelTransport::bind();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::with_transport(transport, 1000, AbortWatchingBehavior { aborted: aborted_tx });
+    receiver.run(rx);
+  });
+
+  let mut session = Session::connect_with(move || Ok(connector.connect()), Duration::from_millis(2000)).unwrap();
+  let data = vec![1_u8; 2500];
+  let reader = FailingReader { data: data.as_slice(), limit: 1500 };
+  match session.send_reader("msg-64", reader, data.len(), Duration::from_millis(2000), false, |_| {}) {
+    Ok(_) => { panic!("Expected the read to fail."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::IO_ERROR(_) => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  }
+  assert_eq!(aborted_rx.recv_timeout(Duration::from_millis(2000)).unwrap(), AbortReason::INTERNAL_ERROR);
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[cfg(feature = "zmq")]
+struct RefusingSink;
+
+#[cfg(feature = "zmq")]
+impl BlobSink for RefusingSink {
+  fn on_start(&mut self, _id: &[u8], _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_chunk(&mut self, _offset: usize, _bytes: &[u8]) -> bool {
+    false
+  }
+
+  fn on_finish(&mut self, _hash_ok: bool) -> bool {
+    panic!("Blob should have been aborted.");
+  }
+}
+
+#[cfg(feature = "zmq")]
+struct RefusingBehavior;
+
+#[cfg(feature = "zmq")]
+impl BlobReceiverBehavior for RefusingBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], _array: &[u8]) {
+    panic!("Blob should have been aborted.");
+  }
+
+  fn new_sink(&mut self, _id: &[u8], _data_size: usize) -> Option<Box<BlobSink>> {
+    Some(Box::new(RefusingSink))
+  }
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn receiver_abort_reaches_sender() {
+  let (tx, rx) = channel();
+
+  let (endpoint, recv_handle) = zmq_receiver(1e6 as usize, RefusingBehavior, rx);
+
+  match send_binary_blob(&endpoint, "msg-12", vec![0x2a_u8; 2.5e6 as usize].as_slice(), Duration::from_millis(20000), false, |s| { info!("{}", s) }) {
+    Ok(_) => { panic!("Expected the receiver to abort."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::ABORTED(reason) => { assert_eq!(reason, AbortReason::CHUNK_REJECTED); },
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
     }
   };
 
-  tx.send(STOP);
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "zmq")]
+fn session_negotiates_capabilities() {
+  let (tx, rx) = channel();
+
+  let (endpoint_tx, endpoint_rx) = channel();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::new("tcp://127.0.0.1:*", 1000, SummingBehavior).unwrap();
+    receiver.machine.capabilities = vec![capabilities::CONS];
+    endpoint_tx.send(receiver.bind_address.clone()).unwrap();
+    receiver.run(rx);
+  });
+
+  let endpoint: String = endpoint_rx.recv().unwrap();
+  let session = Session::connect(&endpoint, Duration::from_millis(2000)).unwrap();
+  assert_eq!(session.peer().version, PROTOCOL_VERSION);
+  assert!(session.peer().supports(capabilities::CONS));
+  assert!(!session.peer().supports(capabilities::RESUME));
+  assert!(!session.peer().supports(capabilities::ABORT));
+
+  tx.send(STOP).unwrap();
   recv_handle.join().unwrap();
 }
+
+#[test]
+#[cfg(feature = "zmq")]
+fn session_refuses_legacy_receiver() {
+  let (sock, endpoint) = fake_receiver_socket();
+  thread::spawn(move || {
+    loop {
+      let mut parts = vec![sock.recv_bytes(0).unwrap()];
+      while sock.get_rcvmore().unwrap() {
+        parts.push(sock.recv_bytes(0).unwrap());
+      }
+
+      let sender_id = parts[0].as_slice();
+      if parts[1].as_slice() == b"PING" {
+        sock.send_multipart([sender_id, b"", b"PONG"], 0).unwrap();
+      }
+    }
+  });
+
+  match Session::connect(&endpoint, Duration::from_millis(2000)) {
+    Ok(_) => { panic!("Expected a legacy receiver to be refused."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::UNSUPPORTED => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  };
+}
+
+#[test]
+fn receiver_refuses_blobs_from_older_senders() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+
+  // A sender from before versions were negotiated only takes a bare PONG, so it gives up on ours.
+  receiver.handle_frames(b"legacy", vec![b"PING".to_vec()], now);
+  receiver.handle_frames(b"legacy", vec![b"START".to_vec(), b"msg-75".to_vec(), b"10".to_vec()], now);
+  let mut replies = vec![];
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    replies.push(msg.encode());
+  }
+  assert_eq!(replies, vec![vec![b"PONG".to_vec(), PROTOCOL_VERSION.to_string().into_bytes()],
+                           vec![b"NOGO".to_vec(), b"msg-75".to_vec(), b"0".to_vec()]]);
+
+  let older = PeerInfo { version: PROTOCOL_VERSION - 1, capabilities: vec![capabilities::CONS.to_vec()] };
+  receiver.handle_message(b"older", Message::Ping { peer: older }, now);
+  let start = Message::Start { blob_id: b"msg-75".to_vec(), data_size: 10, consistent: true, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"older", start, now);
+  match (receiver.poll_transmit(), receiver.poll_transmit()) {
+    (Some((_, Message::Pong { peer })), Some((_, Message::Nogo { .. }))) => {
+      assert_eq!(peer.version, PROTOCOL_VERSION);
+    },
+    other => { panic!("Expected PONG and NOGO, got {:?}", other); }
+  }
+}
+
+#[test]
+fn messages_round_trip() {
+  let messages = vec![
+    Message::Ping { peer: PeerInfo::legacy() },
+    Message::Pong { peer: PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![capabilities::CONS.to_vec()] } },
+    Message::Start { blob_id: b"msg-14".to_vec(), data_size: 1234, consistent: true, hash_algorithms: vec![], chunk_size: None },
+    Message::Start {
+      blob_id: b"msg-14".to_vec(),
+      data_size: 1234,
+      consistent: false,
+      hash_algorithms: vec![hash::XXH64.to_vec(), hash::SHA256.to_vec()],
+      chunk_size: None
+    },
+    Message::Start { blob_id: b"msg-14".to_vec(), data_size: 1234, consistent: false, hash_algorithms: vec![], chunk_size: Some(64) },
+    Message::Start { blob_id: b"msg-14".to_vec(), data_size: 1234, consistent: false, hash_algorithms: vec![], chunk_size: None },
+    Message::Resume {
+      blob_id: b"msg-14".to_vec(),
+      data_size: 1234,
+      consistent: false,
+      hash_algorithms: vec![hash::SHA256.to_vec()],
+      chunk_size: Some(64),
+      token: None
+    },
+    Message::Resume {
+      blob_id: b"msg-14".to_vec(),
+      data_size: 1234,
+      consistent: false,
+      hash_algorithms: vec![],
+      chunk_size: None,
+      token: Some(b"0123abcd".to_vec())
+    },
+    Message::Gogo { blob_id: b"msg-14".to_vec(), chunk_size: 100, hash_algorithm: Some(hash::XXH64.to_vec()), token: None },
+    Message::Gogo { blob_id: b"msg-14".to_vec(), chunk_size: 100, hash_algorithm: None, token: Some(b"0123abcd".to_vec()) },
+    Message::Resumed {
+      blob_id: b"msg-14".to_vec(),
+      chunk_size: 100,
+      offset: 200,
+      prefix_hash: b"abcd".to_vec(),
+      hash_algorithm: None,
+      token: None
+    },
+    Message::Resumed {
+      blob_id: b"msg-14".to_vec(),
+      chunk_size: 100,
+      offset: 200,
+      prefix_hash: b"abcd".to_vec(),
+      hash_algorithm: Some(hash::SHA256.to_vec()),
+      token: Some(b"0123abcd".to_vec())
+    },
+    Message::End { blob_id: b"msg-14".to_vec(), hash: b"abcd".to_vec(), hash_algorithm: Some(hash::SHA256.to_vec()) },
+    Message::Token { blob_id: b"msg-14".to_vec(), written: None, offset: None },
+    Message::Token { blob_id: b"msg-14".to_vec(), written: Some(300), offset: None },
+    Message::Token { blob_id: b"msg-14".to_vec(), written: Some(300), offset: Some(500) },
+    Message::Chunk { blob_id: b"msg-14".to_vec(), data: vec![0, 1, 2], offset: None, crc: None },
+    Message::Chunk { blob_id: b"msg-14".to_vec(), data: vec![0, 1, 2], offset: Some(300), crc: Some(4000000000) },
+    Message::Resend { blob_id: b"msg-14".to_vec(), offset: 300 },
+    Message::Fail { blob_id: b"msg-14".to_vec(), reason: FailReason::HASH_MISMATCH },
+    Message::Abort { blob_id: b"msg-14".to_vec(), reason: AbortReason::EXPIRED },
+    Message::Join { blob_id: b"msg-14".to_vec(), token: b"0123abcd".to_vec() },
+  ];
+
+  for msg in messages {
+    assert_eq!(Message::decode(msg.clone().encode()).unwrap(), msg);
+  }
+}
+
+#[test]
+fn fail_reason_travels_as_a_code() {
+  let fail = Message::Fail { blob_id: b"msg-14".to_vec(), reason: FailReason::COMMIT_FAILED };
+  assert_eq!(fail.encode(), vec![b"FAIL".to_vec(), b"msg-14".to_vec(), b"COMMIT_FAILED".to_vec()]);
+  // From a receiver that knows a reason we don't, or one that sent text.
+  let frames = vec![b"FAIL".to_vec(), b"msg-14".to_vec(), b"Hash mismatch".to_vec()];
+  assert_eq!(Message::decode(frames).unwrap(), Message::Fail { blob_id: b"msg-14".to_vec(), reason: FailReason::UNKNOWN });
+}
+
+#[test]
+fn start_has_no_consistency_flag_for_peer_without_cons() {
+  let peer = PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![capabilities::ABORT.to_vec()] };
+  let mut sender = SenderMachine::start(b"msg-62", 10, false, &peer, &Proposal::default(), Instant::now() + Duration::from_secs(1));
+  let start = loop {
+    match sender.poll_action() {
+      Some(SenderAction::Send(msg)) => break msg,
+      Some(_) => {},
+      None => { panic!("Expected START"); }
+    }
+  };
+  assert_eq!(start.encode(), vec![b"START".to_vec(), b"msg-62".to_vec(), b"10".to_vec()]);
+}
+
+#[test]
+fn wrong_frame_count_is_protocol_violation() {
+  let frames = vec![b"START".to_vec(), b"msg-15".to_vec()];
+  match Message::decode(frames) {
+    Ok(msg) => { panic!("Expected a protocol violation, got {:?}", msg); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::PROTOCOL_VIOLATION { ref expected, ref got, .. } => {
+          assert_eq!(expected, "START (3 frames)");
+          assert_eq!(got, "START (2 frames)");
+        },
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  };
+}
+
+/// Runs a sender machine against a receiver machine with no sockets, handing every message over
+/// at `now`, until the send is done.
+fn run_machines(sender: &mut SenderMachine, receiver: &mut ReceiverMachine, data: &[u8], now: Instant)
+                -> (Result<Vec<u8>, xact::XactError>, Vec<ProgressEvent>) {
+  run_tampered_machines(sender, receiver, data, now, |_| {})
+}
+
+/// Like `run_machines()`, but lets `tamper` change each message on its way to the receiver.
+fn run_tampered_machines<F>(sender: &mut SenderMachine, receiver: &mut ReceiverMachine, data: &[u8], now: Instant,
+                            mut tamper: F) -> (Result<Vec<u8>, xact::XactError>, Vec<ProgressEvent>)
+                            where F: FnMut(&mut Message) {
+  let mut bytes_read = 0;
+  let mut events = vec![];
+  loop {
+    while let Some(action) = sender.poll_action() {
+      match action {
+        SenderAction::Send(mut msg) => {
+          tamper(&mut msg);
+          receiver.handle_message(b"sender", msg, now);
+        },
+        SenderAction::Read(len) => {
+          sender.on_chunk_read(data[bytes_read..bytes_read + len].to_vec(), now);
+          bytes_read += len;
+        },
+        SenderAction::Event(event) => { events.push(event); },
+        SenderAction::SendOn(..) => unreachable!("Blob isn't striped")
+      }
+    }
+    if let Some(result) = sender.take_result() {
+      return (result, events);
+    }
+
+    receiver.handle_timeout(now);
+    let mut replied = false;
+    while let Some((_, msg)) = receiver.poll_transmit() {
+      sender.handle_message(msg, now);
+      replied = true;
+    }
+    assert!(replied, "Sender is waiting, but the receiver has nothing to say.");
+  }
+}
+
+/// Agrees capabilities between the two machines, as PING/PONG would.
+fn handshake(receiver: &mut ReceiverMachine, now: Instant) -> PeerInfo {
+  let ours = PeerInfo { version: PROTOCOL_VERSION, capabilities: capabilities::ALL.iter().map(|c| c.to_vec()).collect() };
+  receiver.handle_message(b"sender", Message::Ping { peer: ours }, now);
+  match receiver.poll_transmit() {
+    Some((_, Message::Pong { peer })) => peer.negotiate(capabilities::ALL),
+    other => { panic!("Expected PONG, got {:?}", other); }
+  }
+}
+
+#[test]
+fn machines_transfer_blob() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  let peer = handshake(&mut receiver, now);
+
+  let data = vec![7_u8; 2500];
+  let mut sender = SenderMachine::start(b"msg-16", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  let (result, events) = run_machines(&mut sender, &mut receiver, &data, now);
+  assert_eq!(result.unwrap(), b"");
+
+  match events.as_slice() {
+    [ProgressEvent::Connected,
+     ProgressEvent::Accepted { chunk_size: 1000, .. },
+     ProgressEvent::ChunkSent { bytes_sent: 1000, .. },
+     ProgressEvent::ChunkSent { bytes_sent: 2000, .. },
+     ProgressEvent::ChunkSent { bytes_sent: 2500, total: 2500, .. },
+     ProgressEvent::Finalizing,
+     ProgressEvent::Done { .. }] => {},
+    _ => { panic!("Unexpected events: {:?}", events); }
+  }
+}
+
+#[test]
+fn machines_transfer_blob_past_replies_to_an_earlier_transfer() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  let peer = handshake(&mut receiver, now);
+
+  let data = vec![7_u8; 2500];
+  let mut sender = SenderMachine::start(b"msg-74", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  sender.expect_stale_replies();
+  // Still queued on the connection from a cancelled send of the same blob.
+  let leftovers = vec![
+    Message::Token { blob_id: b"msg-74".to_vec(), written: Some(1000), offset: None },
+    Message::Resend { blob_id: b"msg-74".to_vec(), offset: 1000 },
+    Message::Abort { blob_id: b"msg-74".to_vec(), reason: AbortReason::EXPIRED },
+  ];
+  for msg in leftovers {
+    sender.handle_message(msg, now);
+  }
+  assert!(!sender.is_done());
+
+  let (result, _) = run_machines(&mut sender, &mut receiver, &data, now);
+  assert_eq!(result.unwrap(), b"");
+}
+
+#[test]
+fn machines_transfer_blob_with_tokens_ahead_of_gogo() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  let peer = handshake(&mut receiver, now);
+
+  let data = vec![7_u8; 2500];
+  let mut sender = SenderMachine::start(b"msg-78", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  while let Some(action) = sender.poll_action() {
+    if let SenderAction::Send(msg) = action {
+      receiver.handle_message(b"sender", msg, now);
+    }
+  }
+  let mut replies = vec![];
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    replies.push(msg);
+  }
+  // As if the connection had put GOGO behind the TOKENs.
+  replies.rotate_left(1);
+  assert_eq!(replies.last().unwrap().command(), b"GOGO");
+  for msg in replies {
+    sender.handle_message(msg, now);
+  }
+
+  let (result, _) = run_machines(&mut sender, &mut receiver, &data, now);
+  assert_eq!(result.unwrap(), b"");
+}
+
+#[test]
+fn machines_expire_quiet_blob() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  // A sender that could RESUME would get much longer.
+  receiver.capabilities.retain(|c| *c != capabilities::RESUME);
+  let peer = handshake(&mut receiver, now);
+
+  let mut sender = SenderMachine::start(b"msg-17", 2500, false, &peer, &Proposal::default(), now + Duration::from_secs(60));
+  while let Some(action) = sender.poll_action() {
+    if let SenderAction::Send(msg) = action {
+      receiver.handle_message(b"sender", msg, now);
+    }
+  }
+
+  // The sender never gets around to sending a chunk.
+  receiver.handle_timeout(now + Duration::from_secs(11));
+  let mut aborted = false;
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Abort { reason, .. } = msg {
+      assert_eq!(reason, AbortReason::EXPIRED);
+      aborted = true;
+    }
+  }
+  assert!(aborted);
+}
+
+#[test]
+fn machines_keep_resumable_blob_past_blob_ttl() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.capabilities = vec![capabilities::RESUME, capabilities::ABORT];
+  handshake(&mut receiver, now);
+
+  let start = Message::Start { blob_id: b"msg-58".to_vec(), data_size: 2500, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"sender", start, now);
+  for _ in 0..2 {
+    let chunk = Message::Chunk { blob_id: b"msg-58".to_vec(), data: vec![7; 1000], offset: None, crc: None };
+    receiver.handle_message(b"sender", chunk, now);
+  }
+  while receiver.poll_transmit().is_some() {}
+
+  // The sender went away for longer than `blob_ttl`, but came back within `resumable_ttl`.
+  let later = now + receiver.blob_ttl + Duration::from_secs(1);
+  receiver.handle_timeout(later);
+  while receiver.poll_transmit().is_some() {}
+  let resume = Message::Resume {
+    blob_id: b"msg-58".to_vec(),
+    data_size: 2500,
+    consistent: false,
+    hash_algorithms: vec![],
+    chunk_size: None,
+    token: None
+  };
+  receiver.handle_message(b"sender", resume, later);
+  match receiver.poll_transmit() {
+    Some((_, Message::Resumed { offset: 2000, .. })) => {},
+    other => { panic!("Expected RESUMED at 2000, got {:?}", other); }
+  }
+
+  receiver.handle_timeout(later + receiver.resumable_ttl);
+  let mut aborted = false;
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Abort { reason, .. } = msg {
+      assert_eq!(reason, AbortReason::EXPIRED);
+      aborted = true;
+    }
+  }
+  assert!(aborted);
+}
+
+/// Starts a blob from `sender_id`, sends its first chunk, and returns the token from GOGO.
+fn start_resumable(receiver: &mut ReceiverMachine, sender_id: &[u8], blob_id: &[u8], now: Instant) -> Vec<u8> {
+  let start = Message::Start { blob_id: blob_id.to_vec(), data_size: 2500, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(sender_id, start, now);
+  let token = match receiver.poll_transmit() {
+    Some((_, Message::Gogo { token: Some(token), .. })) => token,
+    other => { panic!("Expected GOGO with a token, got {:?}", other); }
+  };
+  let chunk = Message::Chunk { blob_id: blob_id.to_vec(), data: vec![7; 1000], offset: None, crc: None };
+  receiver.handle_message(sender_id, chunk, now);
+  while receiver.poll_transmit().is_some() {}
+  token
+}
+
+/// Sends RESUME from `sender_id` and returns the offset from RESUMED.
+fn resumed_offset(receiver: &mut ReceiverMachine, sender_id: &[u8], blob_id: &[u8], token: Option<Vec<u8>>,
+                  now: Instant) -> usize {
+  let resume = Message::Resume {
+    blob_id: blob_id.to_vec(),
+    data_size: 2500,
+    consistent: false,
+    hash_algorithms: vec![],
+    chunk_size: None,
+    token: token
+  };
+  receiver.handle_message(sender_id, resume, now);
+  let offset = match receiver.poll_transmit() {
+    Some((_, Message::Resumed { offset, .. })) => offset,
+    other => { panic!("Expected RESUMED, got {:?}", other); }
+  };
+  while receiver.poll_transmit().is_some() {}
+  offset
+}
+
+#[test]
+fn resume_needs_token_from_another_sender() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.capabilities = vec![capabilities::RESUME];
+  for sender_id in [b"sender-1", b"sender-2", b"sender-3"].iter() {
+    let ours = PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![capabilities::RESUME.to_vec()] };
+    receiver.handle_message(*sender_id, Message::Ping { peer: ours }, now);
+  }
+  while receiver.poll_transmit().is_some() {}
+
+  let token = start_resumable(&mut receiver, b"sender-1", b"msg-59", now);
+  assert_eq!(token.len(), 32);
+  assert_eq!(resumed_offset(&mut receiver, b"sender-2", b"msg-59", None, now), 0, "Another sender, without the token");
+  assert_eq!(resumed_offset(&mut receiver, b"sender-2", b"msg-59", Some(b"0123abcd".to_vec()), now), 0, "A wrong token");
+  assert_eq!(resumed_offset(&mut receiver, b"sender-3", b"msg-59", Some(token), now), 1000);
+
+  start_resumable(&mut receiver, b"sender-1", b"msg-60", now);
+  assert_eq!(resumed_offset(&mut receiver, b"sender-1", b"msg-60", None, now), 1000, "The same sender");
+}
+
+#[test]
+fn progress_percentages_never_divide_by_zero_or_overflow() {
+  let chunk_sent = |bytes_sent, total| ProgressEvent::ChunkSent { bytes_sent: bytes_sent, total: total, elapsed: Duration::from_secs(0) };
+  assert_eq!(chunk_sent(0, 0).percent_repr().unwrap(), "Progress: 100%");
+  assert_eq!(chunk_sent(250, 1000).percent_repr().unwrap(), "Progress: 25%");
+  // 100 * bytes_sent overflows a 32-bit usize from 43 MB on.
+  assert_eq!(chunk_sent(3000000000, 4000000000).percent_repr().unwrap(), "Progress: 75%");
+  assert_eq!(ProgressEvent::Finalizing.percent_repr(), None);
+}
+
+#[test]
+fn machine_times_out() {
+  let now = Instant::now();
+  let mut sender = SenderMachine::start(b"msg-18", 10, false, &PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![] }, &Proposal::default(), now + Duration::from_secs(1));
+  while sender.poll_action().is_some() {}
+
+  sender.handle_timeout(now + Duration::from_millis(999));
+  assert!(!sender.is_done());
+  sender.handle_timeout(now + Duration::from_secs(1));
+  match sender.take_result() {
+    Some(Err(e)) => {
+      match *e.kind() {
+        ErrorKind::TIMEOUT => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    },
+    other => { panic!("Expected a timeout, got {:?}", other); }
+  }
+}
+
+#[test]
+fn session_over_tcp_stream() {
+  let (tx, rx) = channel();
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let listener = StreamListener::new(listener, 1000 + 100).unwrap();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::with_transport(listener, 1000, SummingBehavior);
+    receiver.run(rx);
+  });
+
+  let mut session = Session::connect_with(move || StreamTransport::connect_tcp(addr),
+                                          Duration::from_millis(2000)).unwrap();
+  assert_eq!(session.peer().version, PROTOCOL_VERSION);
+  let data = vec![5_u8; 4500];
+  let result = session.send("msg-19", &data, Duration::from_millis(2000), true, |_| {}).unwrap();
+  assert_eq!(result, b"22500");
+
+  session.reconnect(Duration::from_millis(2000)).unwrap();
+  let result = session.send("msg-20", &data[..10], Duration::from_millis(2000), true, |_| {}).unwrap();
+  assert_eq!(result, b"50");
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+fn full_chunks_with_a_long_blob_id_fit_in_a_stream_message() {
+  let (tx, rx) = channel();
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let listener = StreamListener::new(listener, 1000).unwrap();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::with_transport(listener, 1000, SummingBehavior);
+    receiver.run(rx);
+  });
+
+  // A CHUNK carries the blob id, the offset and the CRC on top of the chunk itself.
+  let blob_id = format!("msg-73-{}", "x".repeat(57));
+  assert_eq!(blob_id.len(), 64);
+  let mut session = Session::connect_with(move || StreamTransport::connect_tcp(addr),
+                                          Duration::from_millis(2000)).unwrap();
+  session.chunk_size = Some(1 << 20);
+  let data = vec![5_u8; 5000];
+  let result = session.send(&blob_id, &data, Duration::from_millis(2000), true, |_| {}).unwrap();
+  assert_eq!(result, b"25000");
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn stream_keeps_messages_whole_after_a_slow_write() {
+  let (a, b) = UnixStream::pair().unwrap();
+  let mut near = StreamTransport::new(a).unwrap();
+  let mut far = StreamTransport::new(b).unwrap();
+
+  // Far more than the socket buffer holds, so most of it has to wait for the far side to read.
+  let big = vec![7_u8; 8 << 20];
+  near.send(b"", &[b"big", &big], Duration::from_millis(0)).unwrap();
+  match near.send(b"", &[b"small"], Duration::from_millis(10)) {
+    Ok(_) => { panic!("Expected the second message to wait for the first."); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::TIMEOUT => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  }
+
+  // Polling writes out the rest.
+  while !far.poll(Duration::from_millis(1)).unwrap() {
+    near.poll(Duration::from_millis(0)).unwrap();
+  }
+  assert_eq!(far.recv().unwrap().1, vec![b"big".to_vec(), big]);
+
+  near.send(b"", &[b"small"], Duration::from_millis(1000)).unwrap();
+  assert_eq!(far.recv().unwrap().1, vec![b"small".to_vec()]);
+}
+
+#[test]
+fn session_over_channel() {
+  let (tx, rx) = channel();
+
+  let (transport, connector) = ChannelTransport::bind();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::with_transport(transport, 1000, SummingBehavior);
+    receiver.run(rx);
+  });
+
+  let mut session = Session::connect_with(move || Ok(connector.connect()), Duration::from_millis(2000)).unwrap();
+  let data = vec![1_u8; 2500];
+  let result = session.send("msg-21", &data, Duration::from_millis(2000), true, |_| {}).unwrap();
+  assert_eq!(result, b"2500");
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+fn session_stripes_blob_across_streams() {
+  let (tx, rx) = channel();
+  let (completed_tx, completed_rx) = channel();
+
+  let (transport, connector) = ChannelTransport::bind();
+  let recv_handle = thread::spawn(move || {
+    let mut receiver = BlobReceiver::with_transport(transport, 100, CollectingBehavior { completed: completed_tx });
+    receiver.run(rx);
+  });
+
+  let mut session = Session::connect_with(move || Ok(connector.connect()), Duration::from_millis(2000)).unwrap();
+  session.streams = 3;
+  let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+  session.send("msg-31", &data, Duration::from_millis(2000), false, |_| {}).unwrap();
+  assert_eq!(completed_rx.recv().unwrap(), data);
+
+  tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+}
+
+#[test]
+fn machines_stripe_blob_across_streams() {
+  let now = Instant::now();
+  let (completed_tx, completed_rx) = channel();
+  let mut receiver = ReceiverMachine::new(1000, CollectingBehavior { completed: completed_tx });
+  let peer = handshake(&mut receiver, now);
+  assert!(peer.supports(capabilities::JOIN));
+
+  let data: Vec<u8> = (0..30500).map(|i| (i % 251) as u8).collect();
+  let mut sender = SenderMachine::start(b"msg-32", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  sender.stripe_across(2);
+  let stream_ids: [&[u8]; 3] = [b"sender", b"stream-1", b"stream-2"];
+  let mut chunks_per_stream = [0; 3];
+  let mut bytes_read = 0;
+  loop {
+    while let Some(action) = sender.poll_action() {
+      let (stream, msg) = match action {
+        SenderAction::Send(msg) => (0, msg),
+        SenderAction::SendOn(stream, msg) => (stream, msg),
+        SenderAction::Read(len) => {
+          sender.on_chunk_read(data[bytes_read..bytes_read + len].to_vec(), now);
+          bytes_read += len;
+          continue;
+        },
+        SenderAction::Event(_) => { continue; }
+      };
+      if let Message::Chunk { .. } = msg {
+        chunks_per_stream[stream] += 1;
+      }
+      receiver.handle_message(stream_ids[stream], msg, now);
+    }
+    if let Some(result) = sender.take_result() {
+      assert_eq!(result.unwrap(), b"");
+      break;
+    }
+
+    receiver.handle_timeout(now);
+    while let Some((sender_id, msg)) = receiver.poll_transmit() {
+      let stream = stream_ids.iter().position(|id| *id == sender_id.as_slice()).unwrap();
+      sender.handle_stream_message(stream, msg, now);
+    }
+  }
+
+  assert!(chunks_per_stream.iter().all(|&n| n > 0), "Chunks per stream: {:?}", chunks_per_stream);
+  assert_eq!(chunks_per_stream.iter().sum::<usize>(), 31);
+  assert_eq!(completed_rx.try_recv().unwrap(), data);
+}
+
+#[test]
+fn machines_transfer_interleaved_blobs_from_one_sender() {
+  let now = Instant::now();
+  let (completed_tx, completed_rx) = channel();
+  let mut receiver = ReceiverMachine::new(1000, CollectingBehavior { completed: completed_tx });
+  let peer = handshake(&mut receiver, now);
+
+  let blobs: [(&[u8], Vec<u8>); 2] = [
+    (b"msg-76", (0..4500).map(|i| (i % 251) as u8).collect()),
+    (b"msg-77", (0..3200).map(|i| (i % 13) as u8).collect()),
+  ];
+  let mut senders = blobs.iter().map(|&(blob_id, ref data)| {
+    SenderMachine::start(blob_id, data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1))
+  }).collect::<Vec<_>>();
+  let mut bytes_read = [0; 2];
+  let mut results = vec![None, None];
+  // Which blob each CHUNK, TOKEN and END that went either way was about, in order.
+  let mut owners = vec![];
+  while results.iter().any(|result| result.is_none()) {
+    // One action from each sender in turn, so that their messages reach the receiver interleaved.
+    let mut busy = true;
+    while busy {
+      busy = false;
+      for (i, sender) in senders.iter_mut().enumerate() {
+        match sender.poll_action() {
+          Some(SenderAction::Send(msg)) => {
+            if msg.command() == b"CHUNK" || msg.command() == b"END" {
+              owners.push(i);
+            }
+            receiver.handle_message(b"sender", msg, now);
+          },
+          Some(SenderAction::Read(len)) => {
+            sender.on_chunk_read(blobs[i].1[bytes_read[i]..bytes_read[i] + len].to_vec(), now);
+            bytes_read[i] += len;
+          },
+          Some(SenderAction::Event(_)) => {},
+          Some(SenderAction::SendOn(..)) => unreachable!("Blob isn't striped"),
+          None => { continue; }
+        }
+        busy = true;
+      }
+    }
+    for (result, sender) in results.iter_mut().zip(senders.iter_mut()) {
+      if result.is_none() {
+        *result = sender.take_result();
+      }
+    }
+
+    receiver.handle_timeout(now);
+    while let Some((_, msg)) = receiver.poll_transmit() {
+      let i = blobs.iter().position(|&(blob_id, _)| msg.blob_id() == Some(blob_id)).unwrap();
+      if msg.command() == b"TOKEN" {
+        owners.push(i);
+      }
+      senders[i].handle_message(msg, now);
+    }
+  }
+
+  for result in results {
+    assert_eq!(result.unwrap().unwrap(), b"");
+  }
+  let switches = owners.windows(2).filter(|pair| pair[0] != pair[1]).count();
+  assert!(switches >= 4, "Messages weren't interleaved: {:?}", owners);
+  let mut completed = vec![completed_rx.try_recv().unwrap(), completed_rx.try_recv().unwrap()];
+  completed.sort_by_key(|data| data.len());
+  assert_eq!(completed, vec![blobs[1].1.clone(), blobs[0].1.clone()]);
+}
+
+#[test]
+fn join_to_unknown_blob_is_nogo() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  handshake(&mut receiver, now);
+
+  receiver.handle_message(b"stream-1", Message::Join { blob_id: b"msg-33".to_vec(), token: b"0123abcd".to_vec() }, now);
+  match receiver.poll_transmit() {
+    Some((ref sender_id, Message::Nogo { .. })) if sender_id.as_slice() == b"stream-1" => {},
+    other => { panic!("Expected NOGO, got {:?}", other); }
+  }
+}
+
+#[test]
+fn join_needs_blob_token() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  handshake(&mut receiver, now);
+  let start = Message::Start { blob_id: b"msg-66".to_vec(), data_size: 100000, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"sender", start, now);
+  let token = match receiver.poll_transmit() {
+    Some((_, Message::Gogo { token: Some(token), .. })) => token,
+    other => { panic!("Expected GOGO with a token, got {:?}", other); }
+  };
+  while receiver.poll_transmit().is_some() {}
+
+  receiver.handle_message(b"stream-1", Message::Join { blob_id: b"msg-66".to_vec(), token: b"0123abcd".to_vec() }, now);
+  match receiver.poll_transmit() {
+    Some((ref sender_id, Message::Nogo { .. })) if sender_id.as_slice() == b"stream-1" => {},
+    other => { panic!("Expected NOGO, got {:?}", other); }
+  }
+
+  receiver.handle_message(b"stream-2", Message::Join { blob_id: b"msg-66".to_vec(), token: token }, now);
+  match receiver.poll_transmit() {
+    Some((ref sender_id, Message::Token { .. })) if sender_id.as_slice() == b"stream-2" => {},
+    other => { panic!("Expected TOKEN, got {:?}", other); }
+  }
+}
+
+struct CollectingBehavior {
+  completed: Sender<Vec<u8>>
+}
+
+impl BlobReceiverBehavior for CollectingBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], array: &[u8]) {
+    self.completed.send(array.to_vec()).unwrap();
+  }
+}
+
+/// Sends `data` over a channel with `faults` injected in both directions, to a receiver that
+/// offers `caps`. Returns what the send returned, and every blob the receiver accepted.
+fn faulty_transfer(data: &[u8], faults: Faults, seed: u64, caps: &[&'static [u8]]) -> (Result<Vec<u8>, xact::XactError>, Vec<Vec<u8>>) {
+  let (stop_tx, stop_rx) = channel();
+  let (completed_tx, completed_rx) = channel();
+
+  let (transport, connector) = ChannelTransport::bind();
+  let caps = caps.to_vec();
+  let recv_handle = thread::spawn(move || {
+    let transport = FaultyTransport::new(transport, faults, seed);
+    let mut receiver = BlobReceiver::with_transport(transport, 100, CollectingBehavior { completed: completed_tx });
+    receiver.machine.capabilities = caps;
+    receiver.run(stop_rx);
+  });
+
+  let timeout = Duration::from_millis(500);
+  let connect = move || Ok(FaultyTransport::new(connector.connect(), faults, seed ^ 0xff));
+  let result = Session::connect_with(connect, timeout).and_then(|mut session| {
+    session.send("msg-22", data, timeout, false, |_| {})
+  });
+
+  stop_tx.send(STOP).unwrap();
+  recv_handle.join().unwrap();
+
+  let mut completed = vec![];
+  while let Ok(blob) = completed_rx.try_recv() {
+    completed.push(blob);
+  }
+  (result, completed)
+}
+
+fn check_faulty_transfers(faults: Faults, seeds: u64, caps: &[&'static [u8]]) -> usize {
+  let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+  let mut successes = 0;
+  for seed in 0..seeds {
+    let start = Instant::now();
+    let (result, completed) = faulty_transfer(&data, faults, seed, caps);
+    assert!(start.elapsed() < Duration::from_secs(3), "Seed {} took {:?}", seed, start.elapsed());
+    for blob in completed.iter() {
+      assert!(blob.as_slice() == data.as_slice(), "Seed {} committed a corrupted blob", seed);
+    }
+
+    match result {
+      Ok(_) => {
+        assert_eq!(completed.len(), 1, "Seed {} succeeded without committing the blob", seed);
+        successes += 1;
+      },
+      Err(e) => {
+        match *e.kind() {
+          ErrorKind::TIMEOUT | ErrorKind::HASH_MISMATCH | ErrorKind::FAILED | ErrorKind::NOGO |
+          ErrorKind::INVALID_RESPONSE | ErrorKind::ABORTED(_) => {
+            info!("Seed {} failed: {}", seed, e);
+          },
+          ref kind => { panic!("Seed {} failed with unexpected error: {:?}", seed, kind); }
+        }
+      }
+    }
+  }
+  successes
+}
+
+#[test]
+fn transfer_without_faults() {
+  assert_eq!(check_faulty_transfers(Faults::none(), 3, capabilities::ALL), 3);
+}
+
+#[test]
+fn transfer_with_light_faults() {
+  let faults = Faults {
+    drop: 0.01,
+    corrupt: 0.0,
+    duplicate: 0.01,
+    delay: 0.05,
+    reorder: 0.01,
+    max_delay: Duration::from_millis(20)
+  };
+  // Lost chunks and TOKENs are asked for again. Only losing a message nothing asks for again,
+  // like GOGO or END, sinks a transfer, and that's rare with faults this light.
+  let successes = check_faulty_transfers(faults, 10, capabilities::ALL);
+  assert!(successes >= 8, "Only {} of 10 transfers went through", successes);
+}
+
+#[test]
+fn transfer_with_heavy_faults() {
+  let faults = Faults {
+    drop: 0.1,
+    corrupt: 0.0,
+    duplicate: 0.1,
+    delay: 0.1,
+    reorder: 0.1,
+    max_delay: Duration::from_millis(50)
+  };
+  let successes = check_faulty_transfers(faults, 10, capabilities::ALL);
+  assert!(successes >= 4, "Only {} of 10 transfers went through", successes);
+}
+
+#[test]
+fn transfer_with_drops_without_ranges() {
+  // Chunks are written in order, so a lost one has to be asked for again before the ones behind it.
+  let without_ranges: Vec<&'static [u8]> = capabilities::ALL.iter().cloned().filter(|&c| c != capabilities::RANGES).collect();
+  let faults = Faults { drop: 0.05, .. Faults::none() };
+  check_faulty_transfers(faults, 10, &without_ranges);
+}
+
+#[test]
+fn transfer_with_every_message_dropped() {
+  let faults = Faults { drop: 1.0, .. Faults::none() };
+  let data = vec![1_u8; 10];
+  let (result, completed) = faulty_transfer(&data, faults, 1, capabilities::ALL);
+  match result {
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::TIMEOUT => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    },
+    Ok(_) => { panic!("Expected a timeout"); }
+  }
+  assert!(completed.is_empty());
+}
+
+#[test]
+fn hashers_match_reference_values() {
+  let cases: Vec<(&[u8], &[u8], &str)> = vec![
+    (hash::XXH64, b"", "ef46db3751d8e999"),
+    (hash::XXH64, b"a", "d24ec4f1a98c6e5b"),
+    (hash::XXH64, b"abc", "44bc2cf5ad770999"),
+    (hash::SHA256, b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+  ];
+  for (algorithm, input, expected) in cases {
+    let mut hasher = hash::new_hasher(algorithm).unwrap();
+    hasher.input(input);
+    assert_eq!(hasher.hex_digest(), expected);
+  }
+  assert!(hash::new_hasher(b"md5").is_none());
+}
+
+#[test]
+fn xxh64_is_the_same_however_the_input_is_split() {
+  let data: Vec<u8> = (0..1000).map(|i| (i * 7 % 256) as u8).collect();
+  let mut whole = hash::new_hasher(hash::XXH64).unwrap();
+  whole.input(&data);
+
+  for &piece_size in [1, 5, 31, 32, 33, 100].iter() {
+    let mut pieces = hash::new_hasher(hash::XXH64).unwrap();
+    for piece in data.chunks(piece_size) {
+      pieces.input(piece);
+    }
+    assert_eq!(pieces.hex_digest(), whole.hex_digest());
+  }
+}
+
+#[test]
+fn crc32c_matches_check_value() {
+  assert_eq!(hash::crc32c(b"123456789"), 0xe3069283);
+  assert_eq!(hash::crc32c(b""), 0);
+}
+
+#[test]
+fn chunk_crc_over_32_bits_is_invalid() {
+  let frames = |crc: u64| vec![b"CHUNK".to_vec(), b"msg-79".to_vec(), vec![1; 10], b"0".to_vec(), crc.to_string().into_bytes()];
+  match Message::decode(frames(1 << 32)) {
+    Ok(msg) => { panic!("Expected an invalid CRC, got {:?}", msg); },
+    Err(e) => {
+      match *e.kind() {
+        ErrorKind::INVALID_RESPONSE => {},
+        ref kind => { panic!("Wrong error kind: {:?}", kind); }
+      }
+    }
+  }
+  match Message::decode(frames(u32::MAX as u64)).unwrap() {
+    Message::Chunk { crc, .. } => { assert_eq!(crc, Some(u32::MAX)); },
+    msg => { panic!("Expected CHUNK, got {:?}", msg); }
+  }
+}
+
+#[test]
+fn receiver_picks_first_offered_hash() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  handshake(&mut receiver, now);
+
+  receiver.handle_message(b"sender", Message::Start {
+    blob_id: b"msg-23".to_vec(),
+    data_size: 10,
+    consistent: false,
+    hash_algorithms: vec![b"blake9".to_vec(), hash::XXH64.to_vec(), hash::SHA256.to_vec()],
+    chunk_size: None
+  }, now);
+  match receiver.poll_transmit() {
+    Some((_, Message::Gogo { hash_algorithm, .. })) => { assert_eq!(hash_algorithm, Some(hash::XXH64.to_vec())); },
+    other => { panic!("Expected GOGO, got {:?}", other); }
+  }
+}
+
+#[test]
+fn machines_transfer_blob_with_xxh64() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  let peer = handshake(&mut receiver, now);
+
+  let data = vec![9_u8; 2500];
+  let mut sender = SenderMachine::start(b"msg-24", data.len(), false, &peer, &Proposal { hash_algorithms: vec![hash::XXH64], chunk_size: None }, now + Duration::from_secs(1));
+  let (result, _) = run_machines(&mut sender, &mut receiver, &data, now);
+  assert_eq!(result.unwrap(), b"");
+}
+
+#[test]
+fn no_common_hash_is_nogo() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.hash_algorithms = vec![hash::SHA256];
+  let peer = handshake(&mut receiver, now);
+
+  let mut sender = SenderMachine::start(b"msg-25", 10, false, &peer, &Proposal { hash_algorithms: vec![hash::XXH64], chunk_size: None }, now + Duration::from_secs(1));
+  let (result, _) = run_machines(&mut sender, &mut receiver, &[0; 10], now);
+  match *result.unwrap_err().kind() {
+    ErrorKind::NOGO => {},
+    ref kind => { panic!("Wrong error kind: {:?}", kind); }
+  }
+}
+
+#[test]
+fn receiver_without_hashes_refuses_non_sha256_send() {
+  let now = Instant::now();
+  let peer = PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![] };
+  let mut sender = SenderMachine::start(b"msg-26", 10, false, &peer, &Proposal { hash_algorithms: vec![hash::XXH64], chunk_size: None }, now + Duration::from_secs(1));
+  while sender.poll_action().is_some() {}
+  match *sender.take_result().unwrap().unwrap_err().kind() {
+    ErrorKind::UNSUPPORTED => {},
+    ref kind => { panic!("Wrong error kind: {:?}", kind); }
+  }
+}
+
+#[test]
+fn corrupted_chunk_is_resent() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  let peer = handshake(&mut receiver, now);
+  assert!(peer.supports(capabilities::CRC));
+
+  let data: Vec<u8> = (0..3500).map(|i| (i % 251) as u8).collect();
+  let mut sender = SenderMachine::start(b"msg-27", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  // Once when its CRC check failed, and again when END overtook it.
+  assert_eq!(resent_offsets(&mut sender, &mut receiver, &data, now), vec![1000, 1000]);
+}
+
+#[test]
+fn corrupted_chunk_is_resent_without_ranges() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.capabilities.retain(|c| *c != capabilities::RANGES);
+  let peer = handshake(&mut receiver, now);
+  assert!(!peer.supports(capabilities::RANGES));
+
+  let data: Vec<u8> = (0..3500).map(|i| (i % 251) as u8).collect();
+  let mut sender = SenderMachine::start(b"msg-28", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  // The corrupted chunk goes again when its CRC check fails and when END overtakes it. The chunks
+  // sent behind it arrived too early to be kept, so they follow, each asked for as the one before
+  // it lands.
+  assert_eq!(resent_offsets(&mut sender, &mut receiver, &data, now), vec![1000, 1000, 2000, 3000]);
+}
+
+/// Runs a transfer with the first chunk at offset 1000 corrupted, and returns the offsets of the
+/// chunks that were resent.
+fn resent_offsets(sender: &mut SenderMachine, receiver: &mut ReceiverMachine, data: &[u8], now: Instant) -> Vec<usize> {
+  let mut corrupted = false;
+  let (result, events) = run_tampered_machines(sender, receiver, data, now, |msg| {
+    if let Message::Chunk { ref mut data, offset: Some(1000), .. } = *msg {
+      if !corrupted {
+        data[10] ^= 0xff;
+        corrupted = true;
+      }
+    }
+  });
+  assert_eq!(result.unwrap(), b"");
+
+  events.iter().filter_map(|event| {
+    match *event {
+      ProgressEvent::ChunkResent { offset } => Some(offset),
+      _ => None
+    }
+  }).collect()
+}
+
+#[test]
+fn chunks_out_of_order_are_reassembled() {
+  let now = Instant::now();
+  let (completed_tx, completed_rx) = channel();
+  let mut receiver = ReceiverMachine::new(1000, CollectingBehavior { completed: completed_tx });
+  let peer = handshake(&mut receiver, now);
+  assert!(peer.supports(capabilities::RANGES));
+
+  let data: Vec<u8> = (0..4500).map(|i| (i % 251) as u8).collect();
+  let mut sender = SenderMachine::start(b"msg-29", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  let mut bytes_read = 0;
+  let mut chunks_sent = 0;
+  loop {
+    let mut outgoing = vec![];
+    while let Some(action) = sender.poll_action() {
+      match action {
+        SenderAction::Send(msg) => { outgoing.push(msg); },
+        SenderAction::Read(len) => {
+          sender.on_chunk_read(data[bytes_read..bytes_read + len].to_vec(), now);
+          bytes_read += len;
+        },
+        SenderAction::Event(ProgressEvent::ChunkSent { .. }) => { chunks_sent += 1; },
+        SenderAction::Event(_) => {},
+        SenderAction::SendOn(..) => unreachable!("Blob isn't striped")
+      }
+    }
+    if let Some(result) = sender.take_result() {
+      assert_eq!(result.unwrap(), b"");
+      break;
+    }
+
+    // Everything the sender had to say arrives backwards, END first.
+    for msg in outgoing.into_iter().rev() {
+      receiver.handle_message(b"sender", msg, now);
+    }
+    receiver.handle_timeout(now);
+    while let Some((_, msg)) = receiver.poll_transmit() {
+      sender.handle_message(msg, now);
+    }
+  }
+
+  assert_eq!(chunks_sent, 5);
+  assert_eq!(completed_rx.try_recv().unwrap(), data);
+}
+
+#[test]
+fn blob_takes_chunks_in_any_order() {
+  let mut blob = Blob::new(b"msg-30", 2500);
+  blob.track_ranges(1000);
+  assert_eq!(blob.missing_chunks(), Vec::<usize>::new());
+
+  assert!(blob.receive_chunk(2000, vec![3; 500]));
+  assert!(blob.has_chunk(2000));
+  assert_eq!(blob.index, 0);
+  assert!(!blob.receive_chunk(1500, vec![2; 1000]), "Chunk isn't on a chunk boundary");
+  assert!(!blob.receive_chunk(1000, vec![2; 999]), "Chunk is too short");
+  assert!(blob.receive_chunk(1000, vec![2; 1000]));
+  assert!(blob.receive_chunk(0, vec![1; 1000]));
+  assert!(blob.is_complete());
+  assert_eq!(&blob.array[995..1005], &[1, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+  assert_eq!(&blob.array[2495..], &[3; 5]);
+}
+
+#[test]
+fn transfer_with_corruption() {
+  let faults = Faults { corrupt: 0.2, .. Faults::none() };
+  check_faulty_transfers(faults, 10, capabilities::ALL);
+}
+
+/// Runs a stream where each chunk takes `rtt` to come back after its TOKEN, and the link can
+/// carry one chunk every `interval`. Returns the window after `chunks` chunks.
+fn simulate_window(mut window: CreditWindow, rtt: Duration, interval: Duration, chunks: usize) -> usize {
+  let start = Instant::now();
+  let mut in_flight: VecDeque<Instant> = VecDeque::new();
+  let mut link_free = start;
+  for _ in 0..window.credits_due() {
+    window.on_issued(start);
+    in_flight.push_back(start + rtt);
+  }
+
+  for _ in 0..chunks {
+    let due = in_flight.pop_front().unwrap();
+    let arrival = cmp::max(due, link_free);
+    link_free = arrival + interval;
+    window.on_arrival(arrival);
+    for _ in 0..window.credits_due() {
+      window.on_issued(arrival);
+      in_flight.push_back(arrival + rtt);
+    }
+  }
+  window.window()
+}
+
+#[test]
+fn credit_window_follows_bandwidth_delay_product() {
+  let ms = Duration::from_millis(1);
+
+  // 1000 chunks a second over a 100 ms round trip needs far more than 10 in flight.
+  assert_eq!(simulate_window(CreditWindow::new(10, 2, 64), ms * 100, ms, 500), 64);
+  assert_eq!(simulate_window(CreditWindow::new(10, 2, 150), ms * 50, ms, 500), 100);
+  // On a 1 ms round trip, a couple are enough.
+  assert_eq!(simulate_window(CreditWindow::new(10, 2, 64), ms, ms, 500), 2);
+  assert_eq!(simulate_window(CreditWindow::new(10, 4, 64), ms, ms, 500), 4);
+}
+
+#[test]
+fn credit_window_starts_within_bounds() {
+  assert_eq!(CreditWindow::new(10, 2, 64).window(), 10);
+  assert_eq!(CreditWindow::new(10, 2, 8).credits_due(), 8);
+  assert_eq!(CreditWindow::new(10, 20, 64).window(), 20);
+  assert_eq!(CreditWindow::new(10, 0, 0).window(), 1);
+}
+
+#[test]
+fn credit_window_writes_off_lost_credits() {
+  let start = Instant::now();
+  let ms = Duration::from_millis(1);
+  let mut window = CreditWindow::new(4, 2, 64);
+  for _ in 0..4 {
+    window.on_issued(start);
+  }
+  let rto = window.rto();
+  assert_eq!(window.expire(start + rto - ms), 0);
+  assert_eq!(window.expire(start + rto), 4);
+  assert_eq!(window.credits_due(), 4);
+
+  // Nothing came back, so the next lot gets twice as long.
+  assert_eq!(window.rto(), rto * 2);
+  let later = start + rto;
+  window.on_issued(later);
+  window.on_issued(later);
+  assert_eq!(window.expire(later + rto), 0);
+  assert_eq!(window.expire(later + rto * 2), 2);
+
+  // A chunk arriving resets the timeout to a few round trips, and keeps the stream alive.
+  let later = later + rto * 2;
+  window.on_issued(later);
+  window.on_issued(later);
+  window.on_arrival(later + ms * 10);
+  assert!(window.rto() < rto);
+  assert_eq!(window.expire(later + ms * 10 + window.rto() - ms), 0);
+  assert_eq!(window.expire(later + ms * 10 + window.rto()), 1);
+}
+
+#[test]
+fn credit_window_writes_off_overtaken_credits() {
+  let now = Instant::now();
+  let mut window = CreditWindow::new(4, 2, 64);
+  for offset in 0..4 {
+    window.on_issued_for(offset * 100, now);
+  }
+  assert_eq!(window.on_arrival_of(200, now), vec![0, 100]);
+  assert_eq!(window.outstanding(), 1);
+  // The lost chunks turning up after all, or twice, doesn't give back credits that aren't out.
+  assert_eq!(window.on_arrival_of(0, now), Vec::<usize>::new());
+  assert_eq!(window.on_arrival_of(200, now), Vec::<usize>::new());
+  assert_eq!(window.outstanding(), 1);
+  assert_eq!(window.on_arrival_of(300, now), Vec::<usize>::new());
+  assert_eq!(window.outstanding(), 0);
+}
+
+#[test]
+fn receiver_asks_again_for_lost_chunks() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.max_window = 2;
+  handshake(&mut receiver, now);
+
+  let start = Message::Start { blob_id: b"msg-68".to_vec(), data_size: 10000, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"sender", start, now);
+  let mut requested = vec![];
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Token { offset: Some(offset), .. } = msg {
+      requested.push(offset);
+    }
+  }
+  assert_eq!(requested, vec![0, 1000]);
+
+  // Neither TOKEN got an answer, so both chunks are asked for again.
+  let later = now + Duration::from_secs(10);
+  receiver.handle_timeout(later);
+  let mut requested = vec![];
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Token { offset: Some(offset), .. } = msg {
+      requested.push(offset);
+    }
+  }
+  assert_eq!(requested, vec![0, 1000]);
+}
+
+#[test]
+fn receiver_hands_out_no_more_than_max_window() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.max_window = 3;
+  handshake(&mut receiver, now);
+
+  let start = Message::Start { blob_id: b"msg-34".to_vec(), data_size: 10000, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"sender", start, now);
+  let mut tokens = 0;
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Token { .. } = msg {
+      tokens += 1;
+    }
+  }
+  assert_eq!(tokens, 3);
+}
+
+/// The chunk size the receiver puts in GOGO for a blob of `data_size` bytes.
+fn chosen_chunk_size(receiver: &mut ReceiverMachine, blob_id: &[u8], data_size: usize, proposed: Option<usize>) -> usize {
+  chosen_chunk_size_for(receiver, b"sender", blob_id, data_size, proposed)
+}
+
+fn chosen_chunk_size_for(receiver: &mut ReceiverMachine, sender_id: &[u8], blob_id: &[u8], data_size: usize,
+                         proposed: Option<usize>) -> usize {
+  let now = Instant::now();
+  let start = Message::Start {
+    blob_id: blob_id.to_vec(),
+    data_size: data_size,
+    consistent: false,
+    hash_algorithms: vec![],
+    chunk_size: proposed
+  };
+  receiver.handle_message(sender_id, start, now);
+  let mut chosen = None;
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Gogo { chunk_size, .. } = msg {
+      chosen = Some(chunk_size);
+    }
+  }
+  chosen.expect("Expected GOGO")
+}
+
+#[test]
+fn receiver_chooses_chunk_size_within_bounds() {
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.min_chunk_size = 100;
+  receiver.max_chunk_size = 5000;
+  handshake(&mut receiver, Instant::now());
+
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-35", 100000, None), 1000);
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-36", 100000, Some(2000)), 2000);
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-37", 100000, Some(10)), 100);
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-38", 100000, Some(1 << 20)), 5000);
+  // A small blob goes in one chunk, however small.
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-39", 9, None), 9);
+}
+
+#[test]
+fn receiver_shrinks_chunks_under_memory_pressure() {
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.min_chunk_size = 100;
+  receiver.max_window = 4;
+  receiver.chunk_memory = 8000;
+  handshake(&mut receiver, Instant::now());
+
+  // The first blob has the memory to itself; the second shares it.
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-40", 100000, None), 1000);
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-41", 100000, None), 1000);
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-42", 100000, None), 666);
+  // Another sender's streams don't eat into this one's share.
+  let ours = PeerInfo { version: PROTOCOL_VERSION, capabilities: capabilities::ALL.iter().map(|c| c.to_vec()).collect() };
+  receiver.handle_message(b"other", Message::Ping { peer: ours }, Instant::now());
+  while receiver.poll_transmit().is_some() {}
+  assert_eq!(chosen_chunk_size_for(&mut receiver, b"other", b"msg-67", 100000, None), 1000);
+  receiver.chunk_memory = 0;
+  assert_eq!(chosen_chunk_size(&mut receiver, b"msg-43", 100000, None), 100);
+}
+
+#[test]
+fn machines_transfer_blob_with_proposed_chunk_size() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.min_chunk_size = 100;
+  let peer = handshake(&mut receiver, now);
+
+  let data = (0..950).map(|i| i as u8).collect::<Vec<u8>>();
+  let mut sender = SenderMachine::start(b"msg-44", data.len(), false, &peer, &Proposal { chunk_size: Some(300), .. Proposal::default() }, now + Duration::from_secs(1));
+  let (result, events) = run_machines(&mut sender, &mut receiver, &data, now);
+  assert_eq!(result.unwrap(), b"");
+  let sent = events.iter().filter_map(|event| match *event {
+    ProgressEvent::ChunkSent { bytes_sent, .. } => Some(bytes_sent),
+    _ => None
+  }).collect::<Vec<usize>>();
+  assert_eq!(sent, vec![300, 600, 900, 950]);
+}
+
+#[test]
+fn sender_proposes_no_chunk_size_to_old_receiver() {
+  let peer = PeerInfo { version: PROTOCOL_VERSION, capabilities: vec![capabilities::HASHES.to_vec()] };
+  let mut sender = SenderMachine::start(b"msg-45", 10, false, &peer, &Proposal { chunk_size: Some(300), .. Proposal::default() }, Instant::now() + Duration::from_secs(1));
+  let mut proposed = None;
+  while let Some(action) = sender.poll_action() {
+    if let SenderAction::Send(Message::Start { chunk_size, .. }) = action {
+      proposed = Some(chunk_size);
+    }
+  }
+  assert_eq!(proposed, Some(None));
+}
+
+/// Sends `data` between the machines in chunks of `chunk_size`, with the receiver offering only
+/// `capabilities`, and returns what the receiver completed.
+fn transfer_in_chunks(blob_id: &[u8], data: &[u8], chunk_size: usize, capabilities: &[&'static [u8]]) -> Vec<u8> {
+  let now = Instant::now();
+  let (completed_tx, completed_rx) = channel();
+  let mut receiver = ReceiverMachine::new(chunk_size, CollectingBehavior { completed: completed_tx });
+  receiver.capabilities = capabilities.to_vec();
+  let peer = handshake(&mut receiver, now);
+
+  let mut sender = SenderMachine::start(blob_id, data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  let (result, events) = run_machines(&mut sender, &mut receiver, data, now);
+  assert_eq!(result.unwrap(), b"");
+  let chunks_sent = events.iter().filter(|event| match **event {
+    ProgressEvent::ChunkSent { .. } => true,
+    _ => false
+  }).count();
+  assert_eq!(chunks_sent, (data.len() + chunk_size - 1) / chunk_size);
+  completed_rx.try_recv().expect("Receiver didn't complete the blob")
+}
+
+#[test]
+fn blobs_of_any_size_arrive_whole() {
+  let without_ranges = capabilities::ALL.iter().cloned().filter(|c| *c != capabilities::RANGES).collect::<Vec<_>>();
+  let legacy = [capabilities::RESUME, capabilities::ABORT, capabilities::HASHES];
+  for &chunk_size in &[1, 7, 64] {
+    for size in (1..200).chain(vec![1000, 4095, 4096, 4097]) {
+      let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+      for caps in [capabilities::ALL, without_ranges.as_slice(), &legacy] {
+        let received = transfer_in_chunks(b"msg-46", &data, chunk_size, caps);
+        assert!(received == data, "Blob of {} bytes in chunks of {} came out wrong", size, chunk_size);
+      }
+    }
+  }
+}
+
+#[test]
+fn short_chunk_is_rejected() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(100, BasicBlobReceiverBehavior);
+  receiver.capabilities = vec![capabilities::ABORT];
+  let peer = handshake(&mut receiver, now);
+
+  let data = vec![3_u8; 250];
+  let mut sender = SenderMachine::start(b"msg-47", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  let (result, _) = run_tampered_machines(&mut sender, &mut receiver, &data, now, |msg| {
+    if let Message::Chunk { ref mut data, .. } = *msg {
+      data.truncate(60);
+    }
+  });
+  match *result.unwrap_err().kind() {
+    ErrorKind::ABORTED(AbortReason::CHUNK_REJECTED) => {},
+    ref kind => { panic!("Expected CHUNK_REJECTED, got {:?}", kind); }
+  }
+}
+
+#[test]
+fn short_chunk_with_good_crc_is_rejected() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(100, BasicBlobReceiverBehavior);
+  let peer = handshake(&mut receiver, now);
+
+  // The sender itself got the length wrong, so asking again wouldn't help.
+  let data = vec![3_u8; 250];
+  let mut sender = SenderMachine::start(b"msg-48", data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  let (result, _) = run_tampered_machines(&mut sender, &mut receiver, &data, now, |msg| {
+    if let Message::Chunk { ref mut data, offset: Some(100), ref mut crc, .. } = *msg {
+      data.truncate(60);
+      *crc = Some(hash::crc32c(data));
+    }
+  });
+  match *result.unwrap_err().kind() {
+    ErrorKind::ABORTED(AbortReason::CHUNK_REJECTED) => {},
+    ref kind => { panic!("Expected CHUNK_REJECTED, got {:?}", kind); }
+  }
+}
+
+#[test]
+fn truncated_blob_fails() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(100, BasicBlobReceiverBehavior);
+  receiver.capabilities = vec![];
+  handshake(&mut receiver, now);
+
+  let start = Message::Start { blob_id: b"msg-49".to_vec(), data_size: 250, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"sender", start, now);
+  for _ in 0..2 {
+    receiver.handle_message(b"sender", Message::Chunk { blob_id: b"msg-49".to_vec(), data: vec![3; 100], offset: None, crc: None }, now);
+  }
+  receiver.handle_message(b"sender", Message::End { blob_id: b"msg-49".to_vec(), hash: b"abcd".to_vec(), hash_algorithm: None }, now);
+  let mut reasons = vec![];
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    if let Message::Fail { reason, .. } = msg {
+      reasons.push(reason);
+    }
+  }
+  assert_eq!(reasons, vec![FailReason::TRUNCATED]);
+}
+
+#[test]
+fn truncated_blob_fails_after_waiting_with_crc() {
+  let now = Instant::now();
+  let mut receiver = ReceiverMachine::new(100, BasicBlobReceiverBehavior);
+  handshake(&mut receiver, now);
+
+  let start = Message::Start { blob_id: b"msg-69".to_vec(), data_size: 250, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(b"sender", start, now);
+  for &offset in &[0, 200] {
+    let data = vec![3; if offset == 200 { 50 } else { 100 }];
+    let crc = Some(hash::crc32c(&data));
+    receiver.handle_message(b"sender", Message::Chunk { blob_id: b"msg-69".to_vec(), data: data, offset: Some(offset), crc: crc }, now);
+  }
+  receiver.handle_message(b"sender", Message::End { blob_id: b"msg-69".to_vec(), hash: b"abcd".to_vec(), hash_algorithm: None }, now);
+  let fails = |receiver: &mut ReceiverMachine| {
+    let mut reasons = vec![];
+    while let Some((_, msg)) = receiver.poll_transmit() {
+      if let Message::Fail { reason, .. } = msg {
+        reasons.push(reason);
+      }
+    }
+    reasons
+  };
+  // The missing chunk might still be on its way.
+  assert!(fails(&mut receiver).is_empty());
+  receiver.handle_timeout(now + receiver.end_wait / 2);
+  assert!(fails(&mut receiver).is_empty());
+  receiver.handle_timeout(now + receiver.end_wait);
+  assert_eq!(fails(&mut receiver), vec![FailReason::CHUNKS_MISSING]);
+}
+
+/// Whether the receiver answers a START from `sender_id` with GOGO rather than NOGO.
+fn admits(receiver: &mut ReceiverMachine, sender_id: &[u8], blob_id: &[u8], data_size: usize) -> bool {
+  let now = Instant::now();
+  let start = Message::Start { blob_id: blob_id.to_vec(), data_size: data_size, consistent: false, hash_algorithms: vec![], chunk_size: None };
+  receiver.handle_message(sender_id, start, now);
+  let mut admitted = None;
+  while let Some((_, msg)) = receiver.poll_transmit() {
+    match msg {
+      Message::Gogo { .. } => { admitted = Some(true); },
+      Message::Nogo { .. } => { admitted = Some(false); },
+      _ => {}
+    }
+  }
+  admitted.expect("Expected GOGO or NOGO")
+}
+
+#[test]
+fn receiver_keeps_blobs_within_memory_limits() {
+  let mut receiver = ReceiverMachine::new(1000, BasicBlobReceiverBehavior);
+  receiver.memory_budget = 10000;
+  receiver.max_blob_memory = 6000;
+  receiver.max_sender_memory = 7000;
+
+  assert!(!admits(&mut receiver, b"sender-1", b"msg-50", 6001));
+  assert!(admits(&mut receiver, b"sender-1", b"msg-51", 5000));
+  assert!(!admits(&mut receiver, b"sender-1", b"msg-52", 2001), "Over the sender's limit");
+  assert!(admits(&mut receiver, b"sender-1", b"msg-52", 2000));
+  assert!(!admits(&mut receiver, b"sender-2", b"msg-53", 3001), "Over the budget");
+  assert!(admits(&mut receiver, b"sender-2", b"msg-53", 3000));
+  // Starting a blob over again replaces it, so only the new size counts.
+  assert!(admits(&mut receiver, b"sender-2", b"msg-53", 3000));
+
+  receiver.handle_message(b"sender-1", Message::Abort { blob_id: b"msg-51".to_vec(), reason: AbortReason::CANCELLED }, Instant::now());
+  assert!(admits(&mut receiver, b"sender-3", b"msg-54", 5000));
+}
+
+#[test]
+fn blob_memory_grows_as_chunks_arrive() {
+  let mut blob = Blob::new(b"msg-55", 2500);
+  assert_eq!(blob.array.capacity(), 0);
+  assert!(blob.write_chunk(&[1; 1000]));
+  assert_eq!(blob.array.len(), 1000);
+  assert!(blob.array.capacity() >= 1000 && blob.array.capacity() < 2500);
+  assert!(blob.write_chunk(&[2; 1000]));
+  assert!(blob.write_chunk(&[3; 500]));
+  assert!(blob.is_complete());
+  assert!(blob.array.capacity() >= 2500);
+  assert_eq!(&blob.array[995..1005], &[1, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+}
+
+#[test]
+fn file_sinks_for_the_same_blob_id_keep_apart() {
+  let dir = env::temp_dir().join("xact-same-blob-id");
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+
+  let mut first = FileSink::new(&dir);
+  let mut second = FileSink::new(&dir);
+  assert!(first.on_start(b"msg-56", 10));
+  assert!(second.on_start(b"msg-56", 10));
+  assert!(first.on_chunk(0, &[1; 10]));
+  assert!(second.on_chunk(0, &[2; 5]));
+  assert!(first.on_finish(true));
+  second.on_abort();
+
+  let mut written = vec![];
+  fs::File::open(dir.join("msg-56")).unwrap().read_to_end(&mut written).unwrap();
+  assert_eq!(written, vec![1; 10]);
+  assert_eq!(partial_files(&dir, "msg-56"), Vec::<String>::new());
+}
+
+#[test]
+fn failed_commit_removes_partial_file() {
+  let dir = env::temp_dir().join("xact-failed-commit");
+  let _ = fs::remove_dir_all(&dir);
+  // A non-empty directory where the blob should go makes the rename fail.
+  fs::create_dir_all(dir.join("msg-57").join("in-the-way")).unwrap();
+
+  let mut sink = FileSink::new(&dir);
+  assert!(sink.on_start(b"msg-57", 10));
+  assert!(sink.on_chunk(0, &[1; 10]));
+  assert!(!sink.on_finish(true));
+  assert_eq!(partial_files(&dir, "msg-57"), Vec::<String>::new());
+}
+
+/// Reports when it's started and aborted.
+struct LoggingSink {
+  log: Sender<&'static str>
+}
+
+impl BlobSink for LoggingSink {
+  fn on_start(&mut self, _id: &[u8], _data_size: usize) -> bool {
+    self.log.send("start").unwrap();
+    true
+  }
+
+  fn on_chunk(&mut self, _offset: usize, _bytes: &[u8]) -> bool {
+    true
+  }
+
+  fn on_finish(&mut self, _hash_ok: bool) -> bool {
+    true
+  }
+
+  fn on_abort(&mut self) {
+    self.log.send("abort").unwrap();
+  }
+}
+
+#[test]
+fn sink_blob_counts_early_chunks_as_memory() {
+  let (log, _) = channel();
+  let mut blob = Blob::with_sink(b"msg-70", 300, Box::new(LoggingSink { log: log }));
+  blob.track_ranges(100);
+  assert_eq!(blob.memory_needed(), 0);
+  assert!(blob.receive_chunk(200, vec![3; 100]));
+  assert_eq!(blob.memory_needed(), 100);
+  assert!(blob.receive_chunk(0, vec![1; 100]));
+  assert_eq!(blob.memory_needed(), 100);
+  assert!(blob.receive_chunk(100, vec![2; 100]));
+  assert_eq!(blob.memory_needed(), 0);
+  assert!(blob.is_complete());
+}
+
+/// Reports how many bytes it's been handed.
+struct TallySink {
+  written: Sender<usize>
+}
+
+impl BlobSink for TallySink {
+  fn on_start(&mut self, _id: &[u8], _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_chunk(&mut self, _offset: usize, bytes: &[u8]) -> bool {
+    self.written.send(bytes.len()).unwrap();
+    true
+  }
+
+  fn on_finish(&mut self, hash_ok: bool) -> bool {
+    hash_ok
+  }
+}
+
+struct TallyBehavior {
+  written: Sender<usize>
+}
+
+impl BlobReceiverBehavior for TallyBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], _array: &[u8]) {}
+
+  fn new_sink(&mut self, _id: &[u8], _data_size: usize) -> Option<Box<BlobSink>> {
+    Some(Box::new(TallySink { written: self.written.clone() }))
+  }
+}
+
+/// Sends a 2000-chunk blob of 100-byte chunks into a sink, losing the first chunk the first time
+/// it's sent. Returns the most the sender ever had read past what the sink had been handed.
+fn most_read_ahead_of_sink(receiver: &mut ReceiverMachine, written_rx: &Receiver<usize>, blob_id: &[u8]) -> usize {
+  let now = Instant::now();
+  let peer = handshake(receiver, now);
+  let data = vec![5_u8; 200000];
+  let mut sender = SenderMachine::start(blob_id, data.len(), false, &peer, &Proposal::default(), now + Duration::from_secs(1));
+  let mut lost = false;
+  let mut bytes_read = 0;
+  let mut written = 0;
+  let mut most_ahead = 0;
+  loop {
+    while let Some(action) = sender.poll_action() {
+      match action {
+        SenderAction::Send(msg) => {
+          if let Message::Chunk { offset: Some(0), .. } = msg {
+            if !lost {
+              lost = true;
+              continue;
+            }
+          }
+          receiver.handle_message(b"sender", msg, now);
+        },
+        SenderAction::Read(len) => {
+          sender.on_chunk_read(data[bytes_read..bytes_read + len].to_vec(), now);
+          bytes_read += len;
+        },
+        SenderAction::Event(_) => {},
+        SenderAction::SendOn(..) => unreachable!("Blob isn't striped")
+      }
+    }
+    if let Some(result) = sender.take_result() {
+      assert_eq!(result.unwrap(), b"");
+      break;
+    }
+
+    written += written_rx.try_iter().sum::<usize>();
+    most_ahead = cmp::max(most_ahead, bytes_read - written);
+    receiver.handle_timeout(now);
+    while let Some((_, msg)) = receiver.poll_transmit() {
+      sender.handle_message(msg, now);
+    }
+  }
+  assert!(lost);
+  written += written_rx.try_iter().sum::<usize>();
+  assert_eq!(written, data.len());
+  most_ahead
+}
+
+#[test]
+fn lost_chunk_holds_back_no_more_than_a_window() {
+  let (written_tx, written_rx) = channel();
+  let mut receiver = ReceiverMachine::new(100, TallyBehavior { written: written_tx });
+  receiver.max_window = 4;
+  let ahead = most_read_ahead_of_sink(&mut receiver, &written_rx, b"msg-71");
+  assert!(ahead <= 4 * 100, "Sender got {} bytes ahead of the sink", ahead);
+}
+
+#[test]
+fn chunks_waiting_for_a_lost_one_stay_within_memory_budget() {
+  let (written_tx, written_rx) = channel();
+  let mut receiver = ReceiverMachine::new(100, TallyBehavior { written: written_tx });
+  receiver.memory_budget = 250;
+  let ahead = most_read_ahead_of_sink(&mut receiver, &written_rx, b"msg-72");
+  // The chunk that's next to write, and two waiting for it.
+  assert!(ahead <= 3 * 100, "Sender got {} bytes ahead of the sink", ahead);
+}
+
+struct LoggingBehavior {
+  log: Sender<&'static str>
+}
+
+impl BlobReceiverBehavior for LoggingBehavior {
+  fn on_ready(&mut self, _data_size: usize) -> bool {
+    true
+  }
+
+  fn on_info(&mut self, msg: &str) {
+    info!("{}", msg);
+  }
+
+  fn on_complete(&mut self, _id: &[u8], _array: &[u8]) {}
+
+  fn new_sink(&mut self, _id: &[u8], _data_size: usize) -> Option<Box<BlobSink>> {
+    Some(Box::new(LoggingSink { log: self.log.clone() }))
+  }
+}
+
+#[test]
+fn restarted_blob_is_aborted_before_new_sink_starts() {
+  let now = Instant::now();
+  let (log_tx, log_rx) = channel();
+  let mut receiver = ReceiverMachine::new(1000, LoggingBehavior { log: log_tx });
+  for _ in 0..2 {
+    let start = Message::Start { blob_id: b"msg-61".to_vec(), data_size: 2500, consistent: false, hash_algorithms: vec![], chunk_size: None };
+    receiver.handle_message(b"sender", start, now);
+  }
+  assert_eq!(log_rx.try_iter().collect::<Vec<_>>(), vec!["start", "abort", "start"]);
+}